@@ -3,10 +3,16 @@ import { handle } from 'hono/aws-lambda';
 
 const app = new Hono();
 const TOKEN_URL = 'http://localhost:8000/my-token';
+// The Function URL is public, so the subject is fixed rather than taken from the caller
+const DEMO_SUBJECT = 'user123';
 
 app.get('/', (c) => c.json({ message: 'Hello Hono!' }));
 app.get('/get-auth-token', async (c) => {
-    const response = await fetch(TOKEN_URL);
+    // Set by lrap-wrapper when the sidecar authenticates its callers
+    const secret = process.env.LRAP_CALLER_SECRET;
+    const response = await fetch(`${TOKEN_URL}?sub=${DEMO_SUBJECT}`, {
+        headers: secret ? { 'Lrap-Caller-Secret': secret } : {},
+    });
     const data = await response.text();
     return c.text(data);
 });
@@ -0,0 +1,198 @@
+//! Caller authentication for the local HTTP server
+//!
+//! Anything in the sandbox can reach the sidecar, including third-party code in the
+//! function and other extensions. Callers can be required to prove who they are, on every
+//! endpoint:
+//!
+//! | Variable                  | Default                                          |
+//! |---------------------------|--------------------------------------------------|
+//! | `LRAP_CALLER_AUTH`        | `none`, or `secret` / `peer`                     |
+//! | `LRAP_CALLER_SECRET_PATH` | `/tmp/lrap-caller-secret`                        |
+//! | `LRAP_CALLER_UIDS`        | required with `peer`, comma separated            |
+//!
+//! With `secret`, a random secret is generated at every init and written to
+//! `LRAP_CALLER_SECRET_PATH` (mode `0600`) before the extension registers, so it is there
+//! when the runtime starts. The `lrap-wrapper` script exports it to the function as
+//! `LRAP_CALLER_SECRET` and deletes the file; callers send it in `Lrap-Caller-Secret`.
+//! Without the wrapper (`AWS_LAMBDA_EXEC_WRAPPER`) nothing deletes it, and the file stays in
+//! `/tmp` for the life of the execution environment.
+//!
+//! The secret only keeps out callers that cannot read the function's environment or that
+//! file, such as code reaching the sidecar through a forwarded port or SSRF. It does not
+//! keep out other extensions: they start alongside the runtime under the same uid, so they
+//! can read the file before the wrapper deletes it, and `LRAP_CALLER_SECRET` afterwards from
+//! `/proc/<runtime pid>/environ`. Nor does it keep out code running inside the function.
+//!
+//! With `peer`, the sidecar only listens on its Unix socket (see [`crate::server`]) and
+//! only answers processes whose uid, as the kernel reports it (`SO_PEERCRED`), is listed
+//! in `LRAP_CALLER_UIDS`. There is no default: the runtime, the function and every
+//! extension usually run as the same uid, which would let all of them in.
+//!
+//! A uid check only tells users apart. Inside a single Lambda sandbox it gives no isolation
+//! between the function and the extensions sharing that uid, and neither mode does: every
+//! extension installed in the function is trusted with the sidecar.
+//!
+//! Other callers get a 401 `unauthenticated`.
+//!
+
+use crate::env::{list_var, optional_var, parsed_var};
+use crate::error::{Error, Result};
+use crate::middleware::Next;
+use crate::oauth::secrets_match;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::{Body, Request, Response};
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::Deserialize;
+use serde_json::Value;
+use std::fs::Permissions;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::str::FromStr;
+
+/// Header carrying the caller secret
+pub const SECRET_HEADER: &str = "Lrap-Caller-Secret";
+
+/// Random bytes in a caller secret
+const SECRET_BYTES: usize = 32;
+
+static CONFIG: OnceCell<CallerAuth> = OnceCell::new();
+
+/// How callers of the local HTTP server prove who they are
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    #[default]
+    None,
+    Secret,
+    Peer,
+}
+
+impl FromStr for Mode {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_owned()))
+    }
+}
+
+/// Credentials of the process on the other end of a Unix socket, kept in the request
+/// extensions
+#[derive(Debug, Clone, Copy)]
+pub struct Peer {
+    pub uid: u32,
+    pub pid: Option<i32>,
+}
+
+/// Caller authentication settings, and the secret of this init
+#[derive(Debug)]
+pub struct CallerAuth {
+    pub mode: Mode,
+    secret: Option<String>,
+    uids: Vec<u32>,
+}
+
+impl CallerAuth {
+    fn from_env() -> Result<Self> {
+        let mode = parsed_var("LRAP_CALLER_AUTH", Mode::None)?;
+
+        let secret = match mode {
+            Mode::Secret => {
+                let path = optional_var("LRAP_CALLER_SECRET_PATH")?;
+                if optional_var("AWS_LAMBDA_EXEC_WRAPPER")?.is_none() {
+                    warn!("No AWS_LAMBDA_EXEC_WRAPPER is set, so the caller secret file is never deleted");
+                }
+                Some(write_secret(path.unwrap_or_else(|| "/tmp/lrap-caller-secret".to_string()))?)
+            }
+            Mode::None | Mode::Peer => None,
+        };
+
+        let mut uids = Vec::new();
+        for uid in list_var("LRAP_CALLER_UIDS")? {
+            uids.push(
+                uid.parse()
+                    .map_err(|_| Error::Config(format!("LRAP_CALLER_UIDS has an invalid uid: {}", uid)))?,
+            );
+        }
+        if mode == Mode::Peer && uids.is_empty() {
+            return Err(Error::Config(
+                "LRAP_CALLER_UIDS is required with LRAP_CALLER_AUTH=peer".to_string(),
+            ));
+        }
+
+        Ok(CallerAuth {
+            mode,
+            secret,
+            uids,
+        })
+    }
+
+    /// Let the request through, or say why the caller is refused
+    pub fn check(&self, req: &Request<Body>) -> Result<()> {
+        let peer = req.extensions().get::<Peer>();
+        match self.mode {
+            Mode::None => Ok(()),
+            Mode::Secret => {
+                let presented = req.headers().get(SECRET_HEADER).and_then(|v| v.to_str().ok());
+                match (presented, &self.secret) {
+                    (Some(presented), Some(secret)) if secrets_match(presented, secret) => Ok(()),
+                    (Some(_), _) => Err(Error::Unauthenticated(format!("{} is wrong", SECRET_HEADER))),
+                    (None, _) => Err(Error::Unauthenticated(format!("Missing {} header", SECRET_HEADER))),
+                }
+            }
+            Mode::Peer => match peer {
+                Some(peer) if self.uids.contains(&peer.uid) => Ok(()),
+                Some(peer) => {
+                    warn!(
+                        "Refusing a caller running as uid {} (pid {})",
+                        peer.uid,
+                        peer.pid.map(|pid| pid.to_string()).unwrap_or_else(|| "-".to_string())
+                    );
+                    Err(Error::Unauthenticated(format!(
+                        "Processes of uid {} may not call the sidecar",
+                        peer.uid
+                    )))
+                }
+                None => Err(Error::Unauthenticated("Caller credentials are unknown".to_string())),
+            },
+        }
+    }
+}
+
+/// Generate this init's caller secret and leave it at `path` for `lrap-wrapper`
+fn write_secret(path: String) -> Result<String> {
+    let mut bytes = [0u8; SECRET_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Config("The system random generator failed".to_string()))?;
+    let secret = URL_SAFE_NO_PAD.encode(bytes);
+
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&path)
+        .and_then(|mut file| {
+            // A file left by an earlier init keeps its mode, so set it again
+            file.set_permissions(Permissions::from_mode(0o600))?;
+            file.write_all(secret.as_bytes())
+        })
+        .map_err(|e| Error::Config(format!("Cannot write the caller secret to {}: {}", path, e)))?;
+
+    info!("Caller secret written to {}", path);
+    Ok(secret)
+}
+
+/// Get the caller authentication settings, generating the secret on first use
+pub fn config() -> Result<&'static CallerAuth> {
+    CONFIG.get_or_try_init(CallerAuth::from_env)
+}
+
+/// Middleware refusing requests from callers that did not authenticate
+pub async fn authenticate(req: Request<Body>, next: Next) -> Result<Response<Body>> {
+    config()?.check(&req)?;
+    next.run(req).await
+}
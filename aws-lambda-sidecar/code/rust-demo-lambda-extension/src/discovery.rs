@@ -0,0 +1,118 @@
+//! Key and metadata publication for token verifiers
+//!
+//! `/.well-known/jwks.json` publishes the public half of every key in the
+//! [`crate::keys::KeyRing`] that is not retired, `/.well-known/jwks/{kid}` a single one of
+//! them, for verifiers that meet a `kid` they have not cached, and
+//! `/.well-known/openid-configuration` describes the issuer.
+//!
+//! The sidecar issues access tokens only, never ID tokens or authorization responses, so the
+//! metadata sticks to RFC 8414 fields backed by its endpoints and leaves out the OpenID
+//! Connect ones (`response_types_supported`, `id_token_signing_alg_values_supported`, ...).
+//!
+
+use crate::error::{Error, Result};
+use crate::http::json_response;
+use crate::server::Listen;
+use hyper::header::{CACHE_CONTROL, HOST};
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::jwk::JwkSet;
+use serde::Serialize;
+
+/// How long verifiers may cache the published metadata
+const CACHE_MAX_AGE: &str = "public, max-age=300";
+
+/// Authorization server metadata (RFC 8414 §2)
+#[derive(Serialize)]
+struct ProviderMetadata {
+    issuer: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    grant_types_supported: Vec<&'static str>,
+    token_endpoint_auth_methods_supported: Vec<&'static str>,
+    revocation_endpoint: String,
+    revocation_endpoint_auth_methods_supported: Vec<&'static str>,
+    introspection_endpoint: String,
+    introspection_endpoint_auth_methods_supported: Vec<&'static str>,
+    dpop_signing_alg_values_supported: Vec<String>,
+}
+
+/// Base URL the caller reached us on, used to build absolute endpoint URLs
+pub fn base_url(req: &Request<Body>) -> String {
+    let host = req.headers().get(HOST).and_then(|h| h.to_str().ok());
+    match (host, crate::server::listen()) {
+        (Some(host), _) => format!("http://{}", host),
+        (None, Ok(Listen::Tcp(addr))) => format!("http://{}", addr),
+        (None, _) => "http://localhost".to_string(),
+    }
+}
+
+/// Attach the caching policy shared by the discovery documents
+fn cacheable(mut response: Response<Body>) -> Response<Body> {
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, hyper::header::HeaderValue::from_static(CACHE_MAX_AGE));
+    response
+}
+
+/// `GET /.well-known/jwks.json`
+///
+/// HMAC secrets are never published, so `HS*` keys are left out of the set.
+///
+pub async fn handle_jwks(_req: Request<Body>) -> Result<Response<Body>> {
+    let mut keys = Vec::new();
+    for key in crate::keys::key_ring()?.published(crate::token::now()) {
+        keys.extend(key.jwk()?);
+    }
+
+    Ok(cacheable(json_response(StatusCode::OK, &JwkSet { keys })?))
+}
+
+/// `GET /.well-known/jwks/{kid}`
+///
+/// Answers 404 for unknown, retired and HMAC keys alike.
+///
+pub async fn handle_jwk(req: Request<Body>) -> Result<Response<Body>> {
+    let kid = crate::router::param(&req, "kid").unwrap_or_default();
+    let key = crate::keys::key_ring()?
+        .published(crate::token::now())
+        .find(|key| key.kid.as_deref() == Some(kid));
+    let jwk = match key {
+        Some(key) => key.jwk()?,
+        None => None,
+    };
+
+    match jwk {
+        Some(jwk) => Ok(cacheable(json_response(StatusCode::OK, &jwk)?)),
+        None => Err(Error::NotFound(format!("No published key with kid '{}'", kid))),
+    }
+}
+
+/// `GET /.well-known/openid-configuration`
+pub async fn handle_openid_configuration(req: Request<Body>) -> Result<Response<Body>> {
+    let config = crate::token::config()?;
+    let base_url = base_url(&req);
+
+    let auth_methods = vec!["client_secret_basic", "client_secret_post"];
+
+    let metadata = ProviderMetadata {
+        issuer: config.issuer.clone(),
+        token_endpoint: format!("{}/oauth/token", base_url),
+        jwks_uri: format!("{}/.well-known/jwks.json", base_url),
+        grant_types_supported: vec![
+            "client_credentials",
+            "refresh_token",
+            "urn:ietf:params:oauth:grant-type:token-exchange",
+        ],
+        token_endpoint_auth_methods_supported: auth_methods.clone(),
+        revocation_endpoint: format!("{}/revoke", base_url),
+        revocation_endpoint_auth_methods_supported: auth_methods.clone(),
+        introspection_endpoint: format!("{}/introspect", base_url),
+        introspection_endpoint_auth_methods_supported: auth_methods,
+        dpop_signing_alg_values_supported: crate::dpop::ALGORITHMS
+            .iter()
+            .map(|alg| format!("{:?}", alg))
+            .collect(),
+    };
+
+    Ok(cacheable(json_response(StatusCode::OK, &metadata)?))
+}
@@ -0,0 +1,191 @@
+//! Sender-constrained tokens with DPoP (RFC 9449)
+//!
+//! A caller that sends a `DPoP` proof to `/my-token` or `/oauth/token` gets a token bound
+//! to the proof's key: it carries the key's thumbprint in `cnf.jkt` (RFC 7638) and is
+//! useless without a fresh proof signed by the same key. `/verify` then enforces the
+//! binding. Refresh tokens from `/my-token` are bound too, since anyone can redeem them;
+//! those of confidential clients are not (§5). Token exchange keeps the binding of a bound
+//! subject token (see [`crate::oauth`]).
+//!
+//! A proof is accepted once, for the method (`htm`) and URL (`htu`) it names, and only
+//! around its `iat`:
+//!
+//! | Variable                     | Default                                  |
+//! |------------------------------|------------------------------------------|
+//! | `DPOP_PROOF_MAX_AGE_SECONDS` | `60`, plus `JWT_LEEWAY_SECONDS` of skew  |
+//!
+
+use crate::env::parsed_var;
+use crate::error::{Error, Result};
+use crate::keys::PublicKey;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::{Body, Request};
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+/// Asymmetric algorithms a proof may be signed with
+pub const ALGORITHMS: [Algorithm; 9] = [
+    Algorithm::ES256,
+    Algorithm::ES384,
+    Algorithm::RS256,
+    Algorithm::RS384,
+    Algorithm::RS512,
+    Algorithm::PS256,
+    Algorithm::PS384,
+    Algorithm::PS512,
+    Algorithm::EdDSA,
+];
+
+static MAX_AGE: OnceCell<u64> = OnceCell::new();
+/// `jti`s of accepted proofs, until they are too old to be accepted anyway
+static SEEN: OnceCell<Mutex<HashMap<String, u64>>> = OnceCell::new();
+
+/// Seconds after its `iat` a proof is still accepted
+pub fn max_age() -> Result<u64> {
+    MAX_AGE
+        .get_or_try_init(|| parsed_var("DPOP_PROOF_MAX_AGE_SECONDS", 60))
+        .copied()
+}
+
+/// Claims of a proof JWT (§4.2)
+#[derive(Debug, Deserialize)]
+struct ProofClaims {
+    jti: String,
+    htm: String,
+    htu: String,
+    iat: u64,
+    ath: Option<String>,
+}
+
+/// Remember `jti` until `until`, failing when it was already used
+fn remember(jti: &str, until: u64) -> Result<()> {
+    let now = crate::token::now();
+    let mut seen = SEEN
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|e| e.into_inner());
+    seen.retain(|_, until| *until > now);
+
+    if seen.contains_key(jti) {
+        return Err(Error::InvalidDpopProof(format!("proof {} was already used", jti)));
+    }
+    seen.insert(jti.to_owned(), until);
+    Ok(())
+}
+
+/// Whether the `jwk` header of `proof` carries private key material
+fn has_private_key(proof: &str) -> bool {
+    proof
+        .split('.')
+        .next()
+        .and_then(|header| URL_SAFE_NO_PAD.decode(header).ok())
+        .and_then(|header| serde_json::from_slice::<Value>(&header).ok())
+        .is_some_and(|header| header["jwk"].get("d").is_some())
+}
+
+/// The URL without its query and fragment, which `htu` leaves out (§4.3)
+fn without_query(url: &str) -> &str {
+    url.split(['?', '#']).next().unwrap_or_default()
+}
+
+/// Check `proof` for a `htm` request to `htu` and return the thumbprint of its key
+///
+/// Pass the access token sent along with the proof, whose hash the proof must carry in
+/// `ath` (§4.3); token requests have none.
+pub fn check_proof(proof: &str, htm: &str, htu: &str, access_token: Option<&str>) -> Result<String> {
+    let header = decode_header(proof).map_err(|e| Error::InvalidDpopProof(format!("malformed proof: {}", e)))?;
+    if header.typ.as_deref() != Some("dpop+jwt") {
+        return Err(Error::InvalidDpopProof("typ must be dpop+jwt".to_string()));
+    }
+    if !ALGORITHMS.contains(&header.alg) {
+        return Err(Error::InvalidDpopProof(format!("{:?} is not an accepted algorithm", header.alg)));
+    }
+    let jwk = header
+        .jwk
+        .ok_or_else(|| Error::InvalidDpopProof("missing jwk header".to_string()))?;
+    if has_private_key(proof) {
+        return Err(Error::InvalidDpopProof("jwk must be a public key".to_string()));
+    }
+    // The same thumbprint as the key ring's `kid`s, so `jkt`s always compare equal
+    let jkt = PublicKey::from_jwk(&jwk)
+        .ok_or_else(|| Error::InvalidDpopProof("jwk must be an asymmetric public key".to_string()))?
+        .thumbprint();
+
+    let key =
+        DecodingKey::from_jwk(&jwk).map_err(|e| Error::InvalidDpopProof(format!("unusable jwk: {}", e)))?;
+    let mut validation = Validation::new(header.alg);
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.set_required_spec_claims::<&str>(&[]);
+    let claims = decode::<ProofClaims>(proof, &key, &validation)
+        .map_err(|e| Error::InvalidDpopProof(format!("proof does not verify: {}", e)))?
+        .claims;
+
+    if claims.htm != htm {
+        return Err(Error::InvalidDpopProof(format!("proof is for {} requests, not {}", claims.htm, htm)));
+    }
+    if without_query(&claims.htu) != without_query(htu) {
+        return Err(Error::InvalidDpopProof(format!(
+            "proof is for {}, not {}",
+            claims.htu,
+            without_query(htu)
+        )));
+    }
+
+    let now = crate::token::now();
+    let leeway = crate::verify::config()?.leeway;
+    let max_age = max_age()?;
+    if claims.iat > now + leeway || claims.iat + max_age + leeway < now {
+        return Err(Error::InvalidDpopProof(format!("iat is more than {} seconds away from now", max_age)));
+    }
+
+    if let Some(token) = access_token {
+        let digest = ring::digest::digest(&ring::digest::SHA256, token.as_bytes());
+        if claims.ath.as_deref() != Some(URL_SAFE_NO_PAD.encode(digest).as_str()) {
+            return Err(Error::InvalidDpopProof("ath does not match the access token".to_string()));
+        }
+    }
+
+    remember(&claims.jti, claims.iat + max_age + leeway)?;
+    Ok(jkt)
+}
+
+/// The `DPoP` header of a request, if any; a request may carry only one (§4.3)
+pub fn proof_header(req: &Request<Body>) -> Result<Option<String>> {
+    let mut proofs = req.headers().get_all("DPoP").iter();
+    let Some(proof) = proofs.next() else {
+        return Ok(None);
+    };
+    if proofs.next().is_some() {
+        return Err(Error::InvalidDpopProof("send a single DPoP header".to_string()));
+    }
+
+    proof
+        .to_str()
+        .map(|proof| Some(proof.trim().to_owned()))
+        .map_err(|_| Error::InvalidDpopProof("malformed DPoP header".to_string()))
+}
+
+/// Check the proof sent to a token endpoint and return the thumbprint to bind tokens to
+pub fn bind(req: &Request<Body>) -> Result<Option<String>> {
+    let Some(proof) = proof_header(req)? else {
+        return Ok(None);
+    };
+    let htu = format!("{}{}", crate::discovery::base_url(req), req.uri().path());
+    check_proof(&proof, req.method().as_str(), &htu, None).map(Some)
+}
+
+/// Bind a token to the key with thumbprint `jkt` through its `cnf` claim (§6.1)
+pub fn confirm(claims: &mut Map<String, Value>, jkt: &str) {
+    claims.insert("cnf".to_string(), json!({ "jkt": jkt }));
+}
+
+/// Thumbprint of the key a token is bound to, `None` for bearer tokens
+pub fn bound_key(claims: &Map<String, Value>) -> Option<&str> {
+    claims.get("cnf")?.get("jkt")?.as_str()
+}
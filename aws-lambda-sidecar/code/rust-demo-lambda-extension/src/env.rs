@@ -8,38 +8,119 @@
 //! Utilities and other helper functions for thread-safe access and lazy initializers
 //!
 
+use crate::error::{Error, Result};
 use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
 
 /// Runtime API endpoint
 static LAMBDA_RUNTIME_API: OnceCell<String> = OnceCell::new();
 
-///Fetches the AWS_LAMBDA_RUNTIME_API environment variable
-pub fn latch_runtime_env() {
-    use std::env::var;
+/// Default Runtime API proxy port, mirrored by the `lrap-wrapper` script
+const DEFAULT_LRAP_LISTENER_PORT: u16 = 9009;
 
-    let aws_lambda_runtime_api =
-        match var("AWS_LAMBDA_RUNTIME_API") {
-            Ok(v) => v,
-            Err(_) => panic!("AWS_LAMBDA_RUNTIME_API not found"),
-        };
+///Fetches the AWS_LAMBDA_RUNTIME_API environment variable
+pub fn latch_runtime_env() -> Result<()> {
+    let aws_lambda_runtime_api = required_var("AWS_LAMBDA_RUNTIME_API")?;
 
     // Latch in the ORIGIN we should proxy to the application
-    LAMBDA_RUNTIME_API.set(aws_lambda_runtime_api.clone())
-        .expect("Expected that mutate_runtime_env() has not been called before, but AWS_LAMBDA_RUNTIME_API was already set");
-
-    
+    LAMBDA_RUNTIME_API.set(aws_lambda_runtime_api).map_err(|_| {
+        Error::Config(
+            "latch_runtime_env() called twice, AWS_LAMBDA_RUNTIME_API was already set".to_string(),
+        )
+    })
 }
 
 /// Gets the original AWS_LAMBDA_RUNTIME_API.
-pub fn sandbox_runtime_api() -> &'static str {
-    match LAMBDA_RUNTIME_API.get() {
-        Some(val) => val,
-        None => {
-            latch_runtime_env();
-            LAMBDA_RUNTIME_API.get().expect(
-                "Error in setting and mutating AWS_LAMBDA_RUNTIME_API environment variables.",
-            )
-        }
+pub fn sandbox_runtime_api() -> Result<&'static str> {
+    LAMBDA_RUNTIME_API
+        .get_or_try_init(|| required_var("AWS_LAMBDA_RUNTIME_API"))
+        .map(String::as_str)
+}
+
+/// Gets the port the Runtime API proxy listens on (`LRAP_LISTENER_PORT`)
+///
+/// The `lrap-wrapper` script reads the same variable to point the function
+/// runtime's AWS_LAMBDA_RUNTIME_API at `127.0.0.1:<port>`.
+pub fn lrap_listener_port() -> Result<u16> {
+    parsed_var("LRAP_LISTENER_PORT", DEFAULT_LRAP_LISTENER_PORT)
+}
+
+/// Demo secret, only accepted in dev mode
+const DEV_JWT_SECRET: &str = "super_secret";
+
+/// Whether weak signing secrets are tolerated for local testing (`LRAP_DEV_MODE=true`)
+pub fn dev_mode() -> Result<bool> {
+    parsed_var("LRAP_DEV_MODE", false)
+}
+
+/// Gets the HMAC secret used to sign tokens
+///
+/// Only dev mode falls back to the demo secret when `JWT_SECRET` is unset.
+pub fn jwt_secret() -> Result<String> {
+    match optional_var("JWT_SECRET")? {
+        Some(secret) => Ok(secret),
+        None if dev_mode()? => Ok(DEV_JWT_SECRET.to_string()),
+        None => Err(Error::Config(
+            "JWT_SECRET is not set; set it, choose an asymmetric JWT_ALGORITHM, or enable LRAP_DEV_MODE".to_string(),
+        )),
+    }
+}
+
+/// Read an environment variable that must be present
+fn required_var(name: &str) -> Result<String> {
+    optional_var(name)?.ok_or_else(|| Error::Config(format!("{} not found", name)))
+}
+
+/// Read and parse an environment variable, using `default` when it is unset
+pub fn parsed_var<T: std::str::FromStr>(name: &str, default: T) -> Result<T> {
+    match optional_var(name)? {
+        Some(value) => value
+            .trim()
+            .parse()
+            .map_err(|_| Error::Config(format!("{} has an invalid value: {}", name, value))),
+        None => Ok(default),
     }
 }
 
+/// Read a comma separated list, ignoring blanks
+pub fn list_var(name: &str) -> Result<Vec<String>> {
+    Ok(optional_var(name)?
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|v| !v.is_empty())
+        .map(str::to_owned)
+        .collect())
+}
+
+/// Read JSON configuration given inline in `name`, or in the file named by `path_name`
+///
+/// Returns the value along with where it came from, or `None` when neither is set.
+pub fn json_var<T: DeserializeOwned>(name: &str, path_name: &str) -> Result<Option<(T, String)>> {
+    let (json, source) = match (optional_var(name)?, optional_var(path_name)?) {
+        (Some(_), Some(_)) => {
+            return Err(Error::Config(format!("Set either {} or {}, not both", name, path_name)));
+        }
+        (Some(json), None) => (json, name.to_string()),
+        (None, Some(path)) => {
+            let json = std::fs::read_to_string(&path)
+                .map_err(|e| Error::Config(format!("Cannot read {} {}: {}", path_name, path, e)))?;
+            (json, path)
+        }
+        (None, None) => return Ok(None),
+    };
+
+    let value = serde_json::from_str(&json).map_err(|e| Error::Config(format!("{} is invalid: {}", source, e)))?;
+    Ok(Some((value, source)))
+}
+
+/// Read an environment variable that may be absent but must be valid unicode when set
+pub fn optional_var(name: &str) -> Result<Option<String>> {
+    use std::env::{var, VarError};
+
+    match var(name) {
+        Ok(v) => Ok(Some(v)),
+        Err(VarError::NotPresent) => Ok(None),
+        Err(VarError::NotUnicode(_)) => Err(Error::Config(format!("{} is not valid unicode", name))),
+    }
+}
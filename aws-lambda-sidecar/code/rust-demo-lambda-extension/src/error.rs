@@ -0,0 +1,289 @@
+//! Errors raised by the Extension (and its HTTP server)
+//!
+//! One error type for the whole crate: configuration, Extensions API transport and
+//! protocol failures, and token signing. Every variant knows how to present itself
+//! to Lambda (as an `Extension.*` error type) and to HTTP callers (as a JSON body).
+//!
+
+use hyper::{Body, Response, StatusCode};
+
+/// Result alias used throughout the extension
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Missing or invalid configuration (environment, secrets, keys)
+    #[error("configuration error: {0}")]
+    Config(String),
+
+    /// The Lambda API could not be reached
+    #[error("transport error: {0}")]
+    Transport(#[from] hyper::Error),
+
+    /// The Lambda API answered with something we did not expect
+    #[error("protocol error: {0}")]
+    Protocol(String),
+
+    /// An HTTP request or response could not be built
+    #[error("invalid HTTP message: {0}")]
+    Http(#[from] hyper::http::Error),
+
+    /// A payload could not be (de)serialized
+    #[error("serialization error: {0}")]
+    Json(#[from] serde_json::Error),
+
+    /// A token could not be signed
+    #[error("signing error: {0}")]
+    Signing(#[from] jsonwebtoken::errors::Error),
+
+    /// A signed token could not be encrypted
+    #[error("encryption error: {0}")]
+    Encryption(String),
+
+    /// A telemetry sink could not deliver a batch
+    #[error("telemetry error: {0}")]
+    Telemetry(String),
+
+    /// The local HTTP server failed to bind or serve
+    #[error("server error: {0}")]
+    Server(hyper::Error),
+
+    /// A presented token failed validation
+    #[error("invalid token: {0}")]
+    InvalidToken(jsonwebtoken::errors::Error),
+
+    /// A presented token names a key that is unknown or no longer active
+    #[error("invalid token: {0}")]
+    UnknownKey(String),
+
+    /// A presented token was revoked before it expired
+    #[error("invalid token: {0}")]
+    TokenRevoked(String),
+
+    /// A presented encrypted token could not be decrypted
+    #[error("invalid token: {0}")]
+    Undecryptable(String),
+
+    /// A DPoP proof is missing, invalid, or made with another key than the token is bound to
+    #[error("invalid DPoP proof: {0}")]
+    InvalidDpopProof(String),
+
+    /// A presented payload signature does not match
+    #[error("invalid signature: {0}")]
+    InvalidSignature(String),
+
+    /// A presented payload signature is outside the replay window
+    #[error("invalid signature: {0}")]
+    SignatureExpired(String),
+
+    /// A token endpoint request was refused, with its RFC 6749 §5.2 error code
+    #[error("{description}")]
+    OAuth { code: &'static str, description: String },
+
+    /// The caller of the local HTTP server did not authenticate
+    #[error("unauthenticated caller: {0}")]
+    Unauthenticated(String),
+
+    /// The caller sent a request we cannot act on
+    #[error("{0}")]
+    BadRequest(String),
+
+    /// No endpoint matches the request
+    #[error("{0}")]
+    NotFound(String),
+
+    /// The endpoint exists but does not answer this method; `allow` lists those it does
+    #[error("{method} is not allowed here, use {allow}")]
+    MethodNotAllowed { method: String, allow: String },
+
+    /// The request body is over the size limit
+    #[error("{0}")]
+    PayloadTooLarge(String),
+
+    /// The request took longer than its route allows
+    #[error("timeout: {0}")]
+    Timeout(String),
+
+    /// A handler panicked while answering the request
+    #[error("internal error: {0}")]
+    Panic(String),
+}
+
+impl Error {
+    /// Refuse a token endpoint request with an RFC 6749 error code
+    pub fn oauth(code: &'static str, description: impl Into<String>) -> Self {
+        Error::OAuth {
+            code,
+            description: description.into(),
+        }
+    }
+
+    /// Whether a presented token was refused, as opposed to the request or the sidecar failing
+    pub fn is_token_rejection(&self) -> bool {
+        matches!(
+            self,
+            Error::InvalidToken(_) | Error::UnknownKey(_) | Error::TokenRevoked(_) | Error::Undecryptable(_)
+        )
+    }
+
+    /// Error type reported through the Extensions API error endpoints
+    pub fn error_type(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "Extension.ConfigError",
+            Error::Transport(_) => "Extension.TransportError",
+            Error::Protocol(_) => "Extension.ProtocolError",
+            Error::Http(_) => "Extension.HttpError",
+            Error::Json(_) => "Extension.SerializationError",
+            Error::Signing(_) => "Extension.SigningError",
+            Error::Encryption(_) => "Extension.EncryptionError",
+            Error::Telemetry(_) => "Extension.TelemetryError",
+            Error::Server(_) => "Extension.ServerError",
+            Error::InvalidToken(_)
+            | Error::UnknownKey(_)
+            | Error::TokenRevoked(_)
+            | Error::Undecryptable(_) => "Extension.InvalidToken",
+            Error::InvalidDpopProof(_) => "Extension.InvalidDpopProof",
+            Error::InvalidSignature(_) | Error::SignatureExpired(_) => "Extension.InvalidSignature",
+            Error::OAuth { .. } => "Extension.OAuthError",
+            Error::Unauthenticated(_) => "Extension.Unauthenticated",
+            Error::BadRequest(_) => "Extension.BadRequest",
+            Error::NotFound(_) => "Extension.NotFound",
+            Error::MethodNotAllowed { .. } => "Extension.MethodNotAllowed",
+            Error::PayloadTooLarge(_) => "Extension.PayloadTooLarge",
+            Error::Timeout(_) => "Extension.Timeout",
+            Error::Panic(_) => "Extension.Crash",
+        }
+    }
+
+    /// Short machine readable code used in JSON error bodies
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Config(_) => "configuration_error",
+            Error::Transport(_) => "transport_error",
+            Error::Protocol(_) => "protocol_error",
+            Error::Http(_) => "http_error",
+            Error::Json(_) => "serialization_error",
+            Error::Signing(_) => "signing_error",
+            Error::Encryption(_) => "encryption_error",
+            Error::Telemetry(_) => "telemetry_error",
+            Error::Server(_) => "server_error",
+            Error::InvalidToken(e) => token_error_code(e),
+            Error::UnknownKey(_) => "unknown_key",
+            Error::TokenRevoked(_) => "token_revoked",
+            Error::Undecryptable(_) => "decryption_failed",
+            Error::InvalidDpopProof(_) => "invalid_dpop_proof",
+            Error::InvalidSignature(_) => "invalid_signature",
+            Error::SignatureExpired(_) => "signature_expired",
+            Error::OAuth { code, .. } => code,
+            Error::Unauthenticated(_) => "unauthenticated",
+            Error::BadRequest(_) => "invalid_request",
+            Error::NotFound(_) => "not_found",
+            Error::MethodNotAllowed { .. } => "method_not_allowed",
+            Error::PayloadTooLarge(_) => "payload_too_large",
+            Error::Timeout(_) => "timeout",
+            Error::Panic(_) => "internal_error",
+        }
+    }
+
+    /// HTTP status returned to callers of the local server
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::InvalidToken(_)
+            | Error::UnknownKey(_)
+            | Error::TokenRevoked(_)
+            | Error::Undecryptable(_)
+            | Error::InvalidDpopProof(_)
+            | Error::InvalidSignature(_)
+            | Error::SignatureExpired(_)
+            | Error::Unauthenticated(_) => StatusCode::UNAUTHORIZED,
+            Error::OAuth { code: "invalid_client", .. } => StatusCode::UNAUTHORIZED,
+            Error::OAuth { .. } | Error::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::MethodNotAllowed { .. } => StatusCode::METHOD_NOT_ALLOWED,
+            Error::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::Timeout(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Transport(_) | Error::Protocol(_) | Error::Telemetry(_) => StatusCode::BAD_GATEWAY,
+            Error::Config(_)
+            | Error::Http(_)
+            | Error::Json(_)
+            | Error::Signing(_)
+            | Error::Encryption(_)
+            | Error::Server(_)
+            | Error::Panic(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Render the error as a structured JSON response
+    pub fn into_response(self) -> Response<Body> {
+        let status = self.status();
+        let body = match &self {
+            // RFC 6749 §5.2 fixes the shape of token endpoint errors
+            Error::OAuth { code, description } => serde_json::json!({
+                "error": code,
+                "error_description": description,
+            }),
+            _ => serde_json::json!({
+                "error": self.code(),
+                "message": self.to_string(),
+                "status": status.as_u16(),
+            }),
+        };
+
+        let mut response = Response::new(Body::from(body.to_string()));
+        *response.status_mut() = status;
+        response.headers_mut().insert(
+            hyper::header::CONTENT_TYPE,
+            hyper::header::HeaderValue::from_static("application/json"),
+        );
+        if self.is_token_rejection() {
+            // RFC 6750 §3: tell bearer token callers why the token was refused
+            let challenge = format!(r#"Bearer error="invalid_token", error_description="{}""#, self.code());
+            if let Ok(value) = hyper::header::HeaderValue::from_str(&challenge) {
+                response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, value);
+            }
+        }
+        if let Error::InvalidDpopProof(_) = self {
+            // RFC 9449 §7.1: name the proof as the problem and the algorithms we accept
+            let algorithms: Vec<String> =
+                crate::dpop::ALGORITHMS.iter().map(|alg| format!("{:?}", alg)).collect();
+            let challenge = format!(r#"DPoP error="invalid_dpop_proof", algs="{}""#, algorithms.join(" "));
+            if let Ok(value) = hyper::header::HeaderValue::from_str(&challenge) {
+                response.headers_mut().insert(hyper::header::WWW_AUTHENTICATE, value);
+            }
+        }
+        if let Error::MethodNotAllowed { allow, .. } = &self {
+            // RFC 9110 §15.5.6: a 405 must list the methods the resource answers
+            if let Ok(value) = hyper::header::HeaderValue::from_str(allow) {
+                response.headers_mut().insert(hyper::header::ALLOW, value);
+            }
+        }
+        if let Error::OAuth { code, .. } = self {
+            let headers = response.headers_mut();
+            headers.insert(hyper::header::CACHE_CONTROL, hyper::header::HeaderValue::from_static("no-store"));
+            if code == "invalid_client" {
+                headers.insert(
+                    hyper::header::WWW_AUTHENTICATE,
+                    hyper::header::HeaderValue::from_static(r#"Basic realm="oauth""#),
+                );
+            }
+        }
+        response
+    }
+}
+
+/// Name the exact reason a token was rejected
+fn token_error_code(error: &jsonwebtoken::errors::Error) -> &'static str {
+    use jsonwebtoken::errors::ErrorKind;
+
+    match error.kind() {
+        ErrorKind::ExpiredSignature => "token_expired",
+        ErrorKind::ImmatureSignature => "token_not_yet_valid",
+        ErrorKind::InvalidSignature => "invalid_signature",
+        ErrorKind::InvalidAudience => "invalid_audience",
+        ErrorKind::InvalidIssuer => "invalid_issuer",
+        ErrorKind::InvalidSubject => "invalid_subject",
+        ErrorKind::InvalidAlgorithm | ErrorKind::MissingAlgorithm => "invalid_algorithm",
+        ErrorKind::MissingRequiredClaim(_) => "missing_claim",
+        _ => "malformed_token",
+    }
+}
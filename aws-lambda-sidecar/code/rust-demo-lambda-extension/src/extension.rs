@@ -1,12 +1,17 @@
 
 
-use hyper::{Body, Error, Request, Response};
+use crate::error::{Error, Result};
+use hyper::{Body, Request, Response};
 use log::info;
 use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 /// Send a request through a {hyper::Client}
-pub async fn send_request(request: Request<Body>) -> Result<Response<Body>, Error> {
-    hyper::Client::new().request(request).await
+pub async fn send_request(request: Request<Body>) -> Result<Response<Body>> {
+    Ok(hyper::Client::new().request(request).await?)
 }
 
 
@@ -14,65 +19,175 @@ pub async fn send_request(request: Request<Body>) -> Result<Response<Body>, Erro
 const EXTENSION_API_VERSION: &str = "2020-01-01";
 static LAMBDA_EXTENSION_IDENTIFIER: OnceCell<String> = OnceCell::new();
 
+/// Set once the extension asked for its first event, which ends the init phase
+static INIT_COMPLETE: AtomicBool = AtomicBool::new(false);
+
+/// Event returned by the Lambda Extensions API `/event/next` endpoint
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "eventType", rename_all = "UPPERCASE")]
+pub enum NextEvent {
+    Invoke(InvokeEvent),
+    Shutdown(ShutdownEvent),
+}
+
+/// Per-invocation context handed to the extension on INVOKE
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InvokeEvent {
+    pub request_id: String,
+    pub deadline_ms: u64,
+    pub invoked_function_arn: String,
+    #[serde(default)]
+    pub tracing: Option<Tracing>,
+}
+
+/// Tracing header propagated with an invocation (X-Ray)
+#[derive(Debug, Clone, Deserialize)]
+pub struct Tracing {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub value: String,
+}
+
+/// Teardown notice handed to the extension on SHUTDOWN
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShutdownEvent {
+    pub shutdown_reason: ShutdownReason,
+    pub deadline_ms: u64,
+}
+
+/// Why Lambda is shutting down the execution environment
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ShutdownReason {
+    Spindown,
+    Timeout,
+    Failure,
+}
+
+impl std::fmt::Display for ShutdownReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            ShutdownReason::Spindown => "spindown",
+            ShutdownReason::Timeout => "timeout",
+            ShutdownReason::Failure => "failure",
+        };
+        f.write_str(reason)
+    }
+}
+
+impl NextEvent {
+    /// Epoch-millis deadline attached to the event
+    pub fn deadline_ms(&self) -> u64 {
+        match self {
+            NextEvent::Invoke(invoke) => invoke.deadline_ms,
+            NextEvent::Shutdown(shutdown) => shutdown.deadline_ms,
+        }
+    }
+
+    /// Convert the epoch-millis `deadlineMs` into a monotonic deadline
+    pub fn deadline(&self) -> Instant {
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as u64)
+            .unwrap_or_default();
+
+        Instant::now() + Duration::from_millis(self.deadline_ms().saturating_sub(now_ms))
+    }
+}
+
+/// Context of the invocation currently being served, if any
+static CURRENT_INVOKE: RwLock<Option<InvokeEvent>> = RwLock::new(None);
+
+/// Remember the invocation Lambda just handed out
+pub fn set_current_invoke(invoke: InvokeEvent) {
+    *CURRENT_INVOKE.write().unwrap_or_else(|e| e.into_inner()) = Some(invoke);
+}
+
+/// Get the context of the invocation currently being served
+pub fn current_invoke() -> Option<InvokeEvent> {
+    CURRENT_INVOKE
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .clone()
+}
+
 fn find_extension_name() -> String {
     crate::EXTENSION_NAME.to_owned()
 }
 
-pub(super) fn extension_id() -> &'static String {
+pub(super) fn extension_id() -> Result<&'static str> {
     LAMBDA_EXTENSION_IDENTIFIER
         .get()
-        .expect("[LRAP:Extension] Lambda Extension Identifier not set!")
+        .map(String::as_str)
+        .ok_or_else(|| Error::Protocol("Lambda Extension Identifier not set".to_string()))
 }
 
 /// Build the Lambda Extensions API endpoint URL
-fn make_uri(path: &str) -> hyper::Uri {
-    hyper::Uri::builder()
+fn make_uri(path: &str) -> Result<hyper::Uri> {
+    Ok(hyper::Uri::builder()
         .scheme("http")
-        .authority(crate::env::sandbox_runtime_api())
+        .authority(crate::env::sandbox_runtime_api()?)
         .path_and_query(format!("/{}/extension{}", EXTENSION_API_VERSION, path))
-        .build()
-        .expect("[LRAP:Extension] Error building Lambda Extensions API endpoint URL")
+        .build()?)
+}
+
+/// Fail with the response body when the Extensions API did not answer with a 2xx
+async fn ensure_success(response: Response<Body>, action: &str) -> Result<Response<Body>> {
+    if response.status().is_success() {
+        return Ok(response);
+    }
+
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body()).await?;
+    Err(Error::Protocol(format!(
+        "Lambda Extensions API {} failed with {}: {}",
+        action,
+        status,
+        String::from_utf8_lossy(&body)
+    )))
 }
 
 /// Register the extension with the Lambda Extensions API
 ///
 /// This is the first step in the extension lifecycle.
 ///
-/// It registers the extension with the Lambda Extensions API and
+/// It registers the extension with the Lambda Extensions API for INVOKE and
+/// SHUTDOWN events and latches the extension identifier.
 ///
-pub async fn register() {
+pub async fn register() -> Result<()> {
     info!("Registering extension");
-    let uri = make_uri("/register");
+    let uri = make_uri("/register")?;
 
-    let body = hyper::Body::from(r#"{"events":["INVOKE"]}"#);
-    let mut request = hyper::Request::builder()
+    let body = hyper::Body::from(r#"{"events":["INVOKE","SHUTDOWN"]}"#);
+    let request = hyper::Request::builder()
         .method("POST")
         .uri(uri)
-        .body(body)
-        .expect("[LRAP:Extension] Cannot create Lambda Extensions API request");
+        // Set Lambda Extension Name header
+        .header("Lambda-Extension-Name", find_extension_name())
+        .body(body)?;
 
-    // Set Lambda Extension Name header
-    request.headers_mut().append(
-        "Lambda-Extension-Name",
-        find_extension_name().try_into().unwrap(),
-    );
-
-    let response = send_request(request)
-        .await
-        .expect("[LRAP:Extension] Cannot send Lambda Extensions API request to register");
+    let response = send_request(request).await?;
+    let response = ensure_success(response, "POST:register").await?;
 
     info!("Extension registered");
 
     let extension_identifier = response
         .headers()
         .get("lambda-extension-identifier")
-        .expect("[LRAP:Extension] Lambda Extensions API response missing 'lambda-extension-identifier' header in Lambda Extensions API POST:register response")
+        .ok_or_else(|| {
+            Error::Protocol(
+                "Lambda Extensions API POST:register response missing 'lambda-extension-identifier' header"
+                    .to_string(),
+            )
+        })?
         .to_str()
-        .unwrap();
+        .map_err(|e| Error::Protocol(format!("Invalid 'lambda-extension-identifier' header: {}", e)))?;
 
     LAMBDA_EXTENSION_IDENTIFIER
         .set(extension_identifier.to_owned())
-        .expect("[LRAP:Extension] Error setting Lambda Extensions API request ID");
+        .map_err(|_| Error::Protocol("Extension registered twice".to_string()))
 }
 
 
@@ -80,23 +195,65 @@ pub async fn register() {
 ///
 /// This is the second step in the extension lifecycle.
 ///
-/// It gets the next event from the Lambda Extensions API and
+/// It blocks until Lambda hands out the next INVOKE or SHUTDOWN event and
+/// returns it as a typed [`NextEvent`].
 ///
-pub async fn get_next() {
-    let uri = make_uri("/event/next");
+pub async fn get_next() -> Result<NextEvent> {
+    let uri = make_uri("/event/next")?;
 
-    let mut request = hyper::Request::builder()
+    let request = hyper::Request::builder()
         .method("GET")
         .uri(uri)
-        .body(Body::empty())
-        .expect("[LRAP:Extension] Cannot create Lambda Extensions API request");
+        .header("Lambda-Extension-Identifier", extension_id()?)
+        .body(Body::empty())?;
+
+    INIT_COMPLETE.store(true, Ordering::SeqCst);
 
-    request.headers_mut().insert(
-        "Lambda-Extension-Identifier",
-        extension_id().try_into().unwrap(),
-    );
+    let response = send_request(request).await?;
+    let response = ensure_success(response, "GET:event/next").await?;
+    let body = hyper::body::to_bytes(response.into_body()).await?;
 
-    // do not care about result because we get next payload through the Runtime API Proxy
-    let _result = send_request(request).await;
+    serde_json::from_slice(&body)
+        .map_err(|e| Error::Protocol(format!("Cannot parse Lambda Extensions API next event: {}", e)))
 }
 
+
+
+/// Report an unrecoverable failure to the Lambda Extensions API
+///
+/// Failures before the first `/event/next` call go to `/extension/init/error`,
+/// later ones to `/extension/exit/error`. Lambda surfaces `error_type` in the
+/// function's error output, so callers are expected to exit right after.
+///
+/// Lambda requires the extension identifier on these endpoints, so failures
+/// before registration cannot be reported.
+///
+pub async fn report_error(error_type: &str, message: &str) -> Result<()> {
+    let extension_id = extension_id()?;
+
+    let path = if INIT_COMPLETE.load(Ordering::SeqCst) {
+        "/exit/error"
+    } else {
+        "/init/error"
+    };
+
+    let body = serde_json::json!({
+        "errorMessage": message,
+        "errorType": error_type,
+        "stackTrace": [],
+    });
+
+    let request = hyper::Request::builder()
+        .method("POST")
+        .uri(make_uri(path)?)
+        .header("Lambda-Extension-Identifier", extension_id)
+        .header("Lambda-Extension-Function-Error-Type", error_type)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))?;
+
+    let response = send_request(request).await?;
+    ensure_success(response, &format!("POST:{}", path.trim_start_matches('/'))).await?;
+
+    info!("Reported {} to {}", error_type, path);
+    Ok(())
+}
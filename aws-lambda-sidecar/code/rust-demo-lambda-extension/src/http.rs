@@ -0,0 +1,52 @@
+//! HTTP helpers shared by the sidecar endpoints
+//!
+
+use crate::error::{Error, Result};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Request, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Render `value` as a JSON response with the given status
+pub fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Result<Response<Body>> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "application/json")
+        .body(Body::from(serde_json::to_string(value)?))?)
+}
+
+/// Parse the query string, treating a missing query as empty
+pub fn query<T: DeserializeOwned>(req: &Request<Body>) -> Result<T> {
+    serde_urlencoded::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| Error::BadRequest(format!("Invalid query string: {}", e)))
+}
+
+/// Read the request body as JSON, returning `None` when it is empty
+pub async fn json_body<T: DeserializeOwned>(req: Request<Body>) -> Result<Option<T>> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(None);
+    }
+
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| Error::BadRequest(format!("Invalid JSON body: {}", e)))
+}
+
+/// Read an `application/x-www-form-urlencoded` request body
+pub async fn form_body<T: DeserializeOwned>(req: Request<Body>) -> Result<T> {
+    let is_form = req
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .is_some_and(|v| v.trim().eq_ignore_ascii_case("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Err(Error::BadRequest(
+            "Expected an application/x-www-form-urlencoded body".to_string(),
+        ));
+    }
+
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    serde_urlencoded::from_bytes(&body).map_err(|e| Error::BadRequest(format!("Invalid form body: {}", e)))
+}
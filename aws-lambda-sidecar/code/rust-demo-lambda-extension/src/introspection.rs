@@ -0,0 +1,150 @@
+//! Token introspection (RFC 7662)
+//!
+//! `POST /introspect` tells gateways whether a token is active and what it carries, for
+//! gateways that cannot validate JWTs themselves. Access tokens go through the same checks
+//! as `/verify`, so a token is active exactly when `/verify` would accept it. Refresh
+//! tokens are active until they are used, revoked or expire.
+//!
+//! Introspection tells whoever asks what a token grants, so callers must authenticate
+//! (§2.1): as a registered OAuth client, or with the sidecar's caller authentication (see
+//! [`crate::auth`]) when it is on. Others get a 401 `invalid_client`. Client credentials
+//! must be valid whenever they are sent.
+//!
+
+use crate::auth::Mode;
+use crate::error::{Error, Result};
+use crate::http::json_response;
+use crate::oauth::{authenticate, basic_credentials, oauth_form};
+use crate::refresh::Grant;
+use hyper::header::{HeaderValue, CACHE_CONTROL};
+use hyper::{Body, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Form parameters of an introspection request (§2.1)
+#[derive(Debug, Default, Deserialize)]
+struct IntrospectionRequest {
+    token: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// Introspection response (§2.2); inactive tokens only report `active: false`
+#[derive(Debug, Default, Serialize)]
+struct IntrospectionResponse {
+    active: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    client_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    token_type: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iat: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    nbf: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sub: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    aud: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iss: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    jti: Option<String>,
+    /// Key a DPoP bound token is tied to (RFC 9449 §6.2)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cnf: Option<Value>,
+}
+
+impl IntrospectionResponse {
+    /// Describe a verified access token from its claims
+    fn from_claims(claims: &Map<String, Value>) -> Self {
+        let string = |name: &str| claims.get(name).and_then(Value::as_str).map(str::to_owned);
+        let number = |name: &str| claims.get(name).and_then(Value::as_u64);
+
+        IntrospectionResponse {
+            active: true,
+            scope: string("scope"),
+            client_id: string("client_id"),
+            token_type: Some(match crate::dpop::bound_key(claims) {
+                Some(_) => "DPoP",
+                None => "Bearer",
+            }),
+            exp: number("exp"),
+            iat: number("iat"),
+            nbf: number("nbf"),
+            sub: string("sub"),
+            aud: claims.get("aud").cloned(),
+            iss: string("iss"),
+            jti: string("jti"),
+            cnf: claims.get("cnf").cloned(),
+        }
+    }
+
+    /// Describe a refresh token that can still be redeemed
+    fn from_grant(grant: &Grant, issuer: &str) -> Self {
+        let aud = match grant.audience.as_slice() {
+            [] => None,
+            [single] => Some(Value::String(single.clone())),
+            many => Some(many.iter().cloned().map(Value::String).collect()),
+        };
+
+        IntrospectionResponse {
+            active: true,
+            scope: grant.claims.get("scope").and_then(Value::as_str).map(str::to_owned),
+            client_id: grant.client_id.clone(),
+            token_type: Some("refresh_token"),
+            exp: Some(grant.expires_at),
+            iat: Some(grant.issued_at),
+            sub: Some(grant.subject.clone()),
+            aud,
+            iss: Some(issuer.to_owned()),
+            ..Default::default()
+        }
+    }
+}
+
+/// Introspect whichever kind of token `token` is
+async fn introspect(token: &str) -> Result<IntrospectionResponse> {
+    if crate::verify::is_access_token(token) {
+        return match crate::verify::validate(token) {
+            Ok(verified) => Ok(IntrospectionResponse::from_claims(&verified.claims)),
+            Err(e) if e.is_token_rejection() => Ok(IntrospectionResponse::default()),
+            Err(e) => Err(e),
+        };
+    }
+
+    let issuer = &crate::token::config()?.issuer;
+    Ok(crate::refresh::lookup(token)
+        .await?
+        .map(|grant| IntrospectionResponse::from_grant(&grant, issuer))
+        .unwrap_or_default())
+}
+
+/// `POST /introspect`
+///
+/// Malformed, unknown and rejected tokens are all reported as `{"active": false}`.
+///
+pub async fn handle_introspect(req: Request<Body>) -> Result<Response<Body>> {
+    let basic = basic_credentials(&req)?;
+    let form: IntrospectionRequest = oauth_form(req).await?;
+    let client = authenticate(basic, form.client_id.as_deref(), form.client_secret.as_deref())?;
+    // The caller auth middleware already refused unauthenticated callers when it is on
+    if client.is_none() && crate::auth::config()?.mode == Mode::None {
+        return Err(Error::oauth("invalid_client", "Introspection requires client authentication"));
+    }
+
+    let token = form
+        .token
+        .as_deref()
+        .ok_or_else(|| Error::oauth("invalid_request", "Missing token"))?;
+    let response = introspect(token).await?;
+
+    let mut response = json_response(StatusCode::OK, &response)?;
+    response
+        .headers_mut()
+        .insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    Ok(response)
+}
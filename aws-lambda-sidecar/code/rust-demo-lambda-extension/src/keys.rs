@@ -0,0 +1,811 @@
+//! Signing keys for the tokens the sidecar issues
+//!
+//! HMAC algorithms sign with `JWT_SECRET`. Asymmetric algorithms load a private key,
+//! either from a PEM or DER file shipped in the layer or from the environment:
+//!
+//! | Variable               | Meaning                                                   |
+//! |------------------------|-----------------------------------------------------------|
+//! | `JWT_ALGORITHM`        | `HS256` (default), `HS384`, `HS512`, `RS256`, `RS384`, `RS512`, `PS256`, `PS384`, `PS512`, `ES256`, `ES384`, `EdDSA` |
+//! | `JWT_PRIVATE_KEY_PATH` | PEM or DER private key file, e.g. `/opt/keys/signing.pem` |
+//! | `JWT_PRIVATE_KEY`      | PEM text, or base64 of the DER bytes                      |
+//! | `JWT_KEY_ID`           | `kid` header; defaults to the RFC 7638 thumbprint for asymmetric keys |
+//! | `JWT_KEYS`             | key ring as inline JSON, replaces the variables above     |
+//! | `JWT_KEYS_PATH`        | key ring as a JSON file, e.g. `/opt/keys/keyring.json`    |
+//!
+//! RSA keys may be PKCS#1 or PKCS#8, EC and Ed25519 keys must be PKCS#8.
+//!
+//! Every key is loaded at init, so a bad one is reported as an init error. HMAC secrets must
+//! be random, such as base64 of 32 or more random bytes: `openssl rand -base64 64` gives
+//! one long enough for every algorithm. A secret must be at least as long as the hash output
+//! (32, 48 or 64 bytes) and carry at least 32 bytes once decoded from hex or base64. Those
+//! bytes must not repeat a pattern and must score at least 128 bits of estimated entropy,
+//! which refuses `0000…`, `abab…` and `super_secret` repeated to length. The estimate only
+//! catches obvious patterns; it cannot prove a secret random. Only `LRAP_DEV_MODE=true`
+//! accepts weaker secrets, with a warning, and the `super_secret` demo default when
+//! `JWT_SECRET` is unset.
+//!
+//! A key ring lists every key with its own `kid` and schedule, in Unix seconds:
+//!
+//! ```json
+//! [
+//!   { "kid": "2026-q3", "algorithm": "RS256", "private_key_path": "/opt/keys/2026-q3.pem",
+//!     "not_before": 1751328000, "retire_at": 1767225600 },
+//!   { "kid": "2026-q4", "algorithm": "RS256", "private_key_path": "/opt/keys/2026-q4.pem",
+//!     "not_before": 1759276800 }
+//! ]
+//! ```
+//!
+//! The active key with the latest `not_before` signs, so a new key takes over on schedule
+//! without a redeploy. Older keys keep verifying, and stay in the JWKS, until `retire_at`.
+//! Keys that are not active yet are published early so verifiers have them cached before
+//! the switch. HMAC entries take `secret`, or `secret_env` naming the variable that holds it.
+//!
+//! PASETO tokens (see [`crate::paseto`]) use the same ring: `v4.public` signs with the
+//! `EdDSA` keys, `v4.local` encrypts with a key derived from each HMAC secret.
+//!
+
+use crate::env::optional_var;
+use crate::error::{Error, Result};
+use base64::engine::general_purpose::{STANDARD, URL_SAFE_NO_PAD};
+use base64::Engine;
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters, Jwk, KeyAlgorithm,
+    OctetKeyPairParameters, PublicKeyUse, RSAKeyParameters,
+};
+use jsonwebtoken::{Algorithm, DecodingKey, EncodingKey, Header};
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use ring::signature::{self, KeyPair};
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+
+static KEY_RING: OnceCell<KeyRing> = OnceCell::new();
+
+/// Fewest bytes an HMAC secret may encode, once decoded from hex or base64
+const MIN_SECRET_BYTES: usize = 32;
+
+/// Least estimated entropy accepted in the decoded bytes of an HMAC secret, in bits
+const MIN_SECRET_ENTROPY_BITS: f64 = 128.0;
+
+/// HKDF info separating the PASETO `v4.local` key from the HMAC secret it comes from
+const LOCAL_KEY_INFO: &[u8] = b"lrap paseto v4.local key";
+
+/// Public half of an asymmetric signing key, as raw JWK members
+#[derive(Debug, Clone)]
+pub enum PublicKey {
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    Ec { crv: &'static str, x: Vec<u8>, y: Vec<u8> },
+    Okp { crv: &'static str, x: Vec<u8> },
+}
+
+impl PublicKey {
+    /// Read the public members of a JWK, `None` for symmetric keys and unknown curves
+    pub fn from_jwk(jwk: &Jwk) -> Option<Self> {
+        let bytes = |value: &str| URL_SAFE_NO_PAD.decode(value).ok();
+        let curve = |curve: &EllipticCurve| match curve {
+            EllipticCurve::P256 => "P-256",
+            EllipticCurve::P384 => "P-384",
+            EllipticCurve::P521 => "P-521",
+            EllipticCurve::Ed25519 => "Ed25519",
+        };
+
+        match &jwk.algorithm {
+            AlgorithmParameters::RSA(params) => Some(PublicKey::Rsa {
+                n: bytes(&params.n)?,
+                e: bytes(&params.e)?,
+            }),
+            AlgorithmParameters::EllipticCurve(params) => Some(PublicKey::Ec {
+                crv: curve(&params.curve),
+                x: bytes(&params.x)?,
+                y: bytes(&params.y)?,
+            }),
+            AlgorithmParameters::OctetKeyPair(params) => Some(PublicKey::Okp {
+                crv: curve(&params.curve),
+                x: bytes(&params.x)?,
+            }),
+            AlgorithmParameters::OctetKey(_) => None,
+        }
+    }
+
+    /// RFC 7638 JWK thumbprint, base64url encoded
+    pub fn thumbprint(&self) -> String {
+        let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+
+        // Required members only, in lexicographic order, without whitespace
+        let canonical = match self {
+            PublicKey::Rsa { n, e } => {
+                format!(r#"{{"e":"{}","kty":"RSA","n":"{}"}}"#, b64(e), b64(n))
+            }
+            PublicKey::Ec { crv, x, y } => format!(
+                r#"{{"crv":"{}","kty":"EC","x":"{}","y":"{}"}}"#,
+                crv,
+                b64(x),
+                b64(y)
+            ),
+            PublicKey::Okp { crv, x } => {
+                format!(r#"{{"crv":"{}","kty":"OKP","x":"{}"}}"#, crv, b64(x))
+            }
+        };
+
+        b64(ring::digest::digest(&ring::digest::SHA256, canonical.as_bytes()).as_ref())
+    }
+
+    /// Publish the key as a signature-verification JWK
+    pub fn to_jwk(&self, kid: Option<String>, algorithm: Algorithm) -> Result<Jwk> {
+        let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+
+        let algorithm_parameters = match self {
+            PublicKey::Rsa { n, e } => AlgorithmParameters::RSA(RSAKeyParameters {
+                n: b64(n),
+                e: b64(e),
+                ..Default::default()
+            }),
+            PublicKey::Ec { crv, x, y } => AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                curve: if *crv == "P-384" { EllipticCurve::P384 } else { EllipticCurve::P256 },
+                x: b64(x),
+                y: b64(y),
+                ..Default::default()
+            }),
+            PublicKey::Okp { x, .. } => AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                curve: EllipticCurve::Ed25519,
+                x: b64(x),
+                ..Default::default()
+            }),
+        };
+
+        let key_algorithm: KeyAlgorithm = format!("{:?}", algorithm)
+            .parse()
+            .map_err(|_| Error::Config(format!("{:?} cannot be published as a JWK", algorithm)))?;
+
+        Ok(Jwk {
+            common: CommonParameters {
+                public_key_use: Some(PublicKeyUse::Signature),
+                key_algorithm: Some(key_algorithm),
+                key_id: kid,
+                ..Default::default()
+            },
+            algorithm: algorithm_parameters,
+        })
+    }
+}
+
+/// One key ring entry, or the single key described by the `JWT_*` variables
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct KeySpec {
+    kid: Option<String>,
+    algorithm: Option<String>,
+    secret: Option<String>,
+    secret_env: Option<String>,
+    private_key: Option<String>,
+    private_key_path: Option<String>,
+    #[serde(default)]
+    not_before: u64,
+    retire_at: Option<u64>,
+    /// Where the entry came from, `None` for the single-key variables
+    #[serde(skip)]
+    origin: Option<String>,
+}
+
+impl KeySpec {
+    /// The single key described by `JWT_ALGORITHM`, `JWT_SECRET` and friends
+    fn from_env() -> Result<Self> {
+        let mut spec = KeySpec {
+            kid: optional_var("JWT_KEY_ID")?,
+            private_key: optional_var("JWT_PRIVATE_KEY")?,
+            private_key_path: optional_var("JWT_PRIVATE_KEY_PATH")?,
+            ..Default::default()
+        };
+        if is_hmac(spec.algorithm()?) {
+            spec.secret = Some(crate::env::jwt_secret()?);
+        }
+        Ok(spec)
+    }
+
+    /// Name a field the way the operator wrote it, for error messages
+    fn field(&self, name: &str) -> String {
+        match &self.origin {
+            Some(origin) => format!("{}.{}", origin, name),
+            None => format!("JWT_{}", name.to_uppercase()),
+        }
+    }
+
+    /// The entry's algorithm, defaulting to `JWT_ALGORITHM` and then `HS256`
+    fn algorithm(&self) -> Result<Algorithm> {
+        match &self.algorithm {
+            Some(name) => parse_algorithm(name),
+            None => parse_algorithm(optional_var("JWT_ALGORITHM")?.as_deref().unwrap_or("HS256")),
+        }
+    }
+
+    /// The shared secret of an HMAC entry, along with where it came from
+    fn hmac_secret(&self) -> Result<(String, String)> {
+        if let Some(secret) = &self.secret {
+            return Ok((secret.clone(), self.field("secret")));
+        }
+        if let Some(name) = &self.secret_env {
+            let field = self.field("secret_env");
+            let secret = optional_var(name)?
+                .ok_or_else(|| Error::Config(format!("{} names {}, which is not set", field, name)))?;
+            return Ok((secret, name.clone()));
+        }
+        Err(Error::Config(format!(
+            "HMAC keys require {} or {}",
+            self.field("secret"),
+            self.field("secret_env")
+        )))
+    }
+
+    /// Read the entry's private key as DER, along with where it came from
+    fn private_key_der(&self) -> Result<(Vec<u8>, String)> {
+        let path_field = self.field("private_key_path");
+        let inline_field = self.field("private_key");
+        read_private_key(
+            self.private_key_path.as_deref(),
+            &path_field,
+            self.private_key.as_deref(),
+            &inline_field,
+        )?
+        .ok_or_else(|| {
+            Error::Config(format!(
+                "Asymmetric algorithms require {} or {}",
+                path_field, inline_field
+            ))
+        })
+    }
+}
+
+/// A key tokens are signed with, how to describe it in the JWT header, and when it is in use
+pub struct SigningKey {
+    pub algorithm: Algorithm,
+    pub kid: Option<String>,
+    pub public: Option<PublicKey>,
+    pub not_before: u64,
+    pub retire_at: Option<u64>,
+    encoding: EncodingKey,
+    decoding: DecodingKey,
+    /// Signs PASETO `v4.public` tokens, for `EdDSA` keys
+    ed25519: Option<signature::Ed25519KeyPair>,
+    /// Encrypts PASETO `v4.local` tokens, for HMAC keys
+    local_key: Option<[u8; 32]>,
+}
+
+impl SigningKey {
+    /// Load the key an entry describes
+    fn load(spec: &KeySpec) -> Result<Self> {
+        let algorithm = spec.algorithm()?;
+        let kid = spec.kid.clone().filter(|kid| !kid.is_empty());
+        if spec.retire_at.is_some_and(|retire_at| retire_at <= spec.not_before) {
+            return Err(Error::Config(format!(
+                "{} must be later than {}",
+                spec.field("retire_at"),
+                spec.field("not_before")
+            )));
+        }
+
+        if is_hmac(algorithm) {
+            let (secret, source) = spec.hmac_secret()?;
+            check_secret_strength(algorithm, &secret, &source, crate::env::dev_mode()?)?;
+            return Ok(SigningKey {
+                algorithm,
+                kid,
+                public: None,
+                not_before: spec.not_before,
+                retire_at: spec.retire_at,
+                encoding: EncodingKey::from_secret(secret.as_bytes()),
+                decoding: DecodingKey::from_secret(secret.as_bytes()),
+                ed25519: None,
+                local_key: Some(derive_local_key(secret.as_bytes())?),
+            });
+        }
+
+        let (der, source) = spec.private_key_der()?;
+        let (encoding, public) = load_asymmetric(algorithm, &der)
+            .map_err(|e| Error::Config(format!("Invalid {:?} private key in {}: {}", algorithm, source, e)))?;
+
+        let ed25519 = match algorithm {
+            Algorithm::EdDSA => Some(
+                signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(&der)
+                    .map_err(|e| Error::Config(format!("Invalid EdDSA private key in {}: {}", source, e)))?,
+            ),
+            _ => None,
+        };
+
+        let kid = kid.or_else(|| Some(public.thumbprint()));
+        let decoding = DecodingKey::from_jwk(&public.to_jwk(kid.clone(), algorithm)?)
+            .map_err(|e| Error::Config(format!("Cannot derive the verification key from {}: {}", source, e)))?;
+
+        Ok(SigningKey {
+            algorithm,
+            kid,
+            public: Some(public),
+            not_before: spec.not_before,
+            retire_at: spec.retire_at,
+            encoding,
+            decoding,
+            ed25519,
+            local_key: None,
+        })
+    }
+
+    /// JWT header announcing the algorithm and key id
+    pub fn header(&self) -> Header {
+        let mut header = Header::new(self.algorithm);
+        header.kid = self.kid.clone();
+        header
+    }
+
+    pub fn encoding_key(&self) -> &EncodingKey {
+        &self.encoding
+    }
+
+    pub fn decoding_key(&self) -> &DecodingKey {
+        &self.decoding
+    }
+
+    pub fn ed25519_key_pair(&self) -> Option<&signature::Ed25519KeyPair> {
+        self.ed25519.as_ref()
+    }
+
+    pub fn local_key(&self) -> Option<&[u8; 32]> {
+        self.local_key.as_ref()
+    }
+
+    /// The public JWK verifiers need, `None` for shared HMAC secrets
+    pub fn jwk(&self) -> Result<Option<Jwk>> {
+        self.public
+            .as_ref()
+            .map(|public| public.to_jwk(self.kid.clone(), self.algorithm))
+            .transpose()
+    }
+
+    /// Whether the key may sign and verify at `at`
+    pub fn is_active(&self, at: u64) -> bool {
+        self.not_before <= at && !self.is_retired(at)
+    }
+
+    /// Whether the key is past its `retire_at`
+    pub fn is_retired(&self, at: u64) -> bool {
+        self.retire_at.is_some_and(|retire_at| at >= retire_at)
+    }
+}
+
+/// Every configured key, latest `not_before` first
+pub struct KeyRing {
+    keys: Vec<SigningKey>,
+    /// Index of the last key handed out for signing, to log promotions
+    last_primary: Mutex<Option<usize>>,
+}
+
+impl KeyRing {
+    /// Load the ring from `JWT_KEYS` / `JWT_KEYS_PATH`, or the single key from `JWT_*`
+    fn from_env() -> Result<Self> {
+        let Some((mut specs, source)) = crate::env::json_var::<Vec<KeySpec>>("JWT_KEYS", "JWT_KEYS_PATH")? else {
+            return KeyRing::new(vec![SigningKey::load(&KeySpec::from_env()?)?]);
+        };
+
+        let mut keys = Vec::with_capacity(specs.len());
+        for (i, spec) in specs.iter_mut().enumerate() {
+            spec.origin = Some(format!("{}[{}]", source, i));
+            keys.push(SigningKey::load(spec)?);
+        }
+
+        if keys.len() > 1 {
+            let mut seen = HashSet::new();
+            for (spec, key) in specs.iter().zip(&keys) {
+                let kid = key
+                    .kid
+                    .as_deref()
+                    .ok_or_else(|| Error::Config(format!("{} is required in a key ring", spec.field("kid"))))?;
+                if !seen.insert(kid) {
+                    return Err(Error::Config(format!("{} repeats the kid '{}'", spec.field("kid"), kid)));
+                }
+            }
+        }
+
+        KeyRing::new(keys)
+    }
+
+    fn new(mut keys: Vec<SigningKey>) -> Result<Self> {
+        if keys.is_empty() {
+            return Err(Error::Config("The key ring is empty".to_string()));
+        }
+        keys.sort_by_key(|key| std::cmp::Reverse(key.not_before));
+
+        Ok(KeyRing {
+            keys,
+            last_primary: Mutex::new(None),
+        })
+    }
+
+    /// The key that signs at `at`: the active key that became valid last
+    pub fn primary(&self, at: u64) -> Option<&SigningKey> {
+        self.keys.iter().find(|key| key.is_active(at))
+    }
+
+    /// The key a token names in its `kid` header, if it is active at `at`
+    ///
+    /// Tokens without a `kid` can only come from a single unnamed key, so they get the primary.
+    pub fn verification_key(&self, kid: Option<&str>, at: u64) -> Option<&SigningKey> {
+        match kid {
+            Some(kid) => self
+                .keys
+                .iter()
+                .find(|key| key.kid.as_deref() == Some(kid) && key.is_active(at)),
+            None => self.primary(at),
+        }
+    }
+
+    /// Keys verifiers should know about at `at`: everything not yet retired
+    pub fn published(&self, at: u64) -> impl Iterator<Item = &SigningKey> {
+        self.keys.iter().filter(move |key| !key.is_retired(at))
+    }
+}
+
+/// Load a JSON list of keys, entries as in the key ring, each named by its required `kid`
+///
+/// For keys that sign something other than tokens, so they never mix with the key ring.
+/// Returns an empty map when neither variable is set.
+pub fn named_keys(name: &str, path_name: &str) -> Result<HashMap<String, SigningKey>> {
+    let Some((mut specs, source)) = crate::env::json_var::<Vec<KeySpec>>(name, path_name)? else {
+        return Ok(HashMap::new());
+    };
+
+    let mut keys = HashMap::with_capacity(specs.len());
+    for (i, spec) in specs.iter_mut().enumerate() {
+        spec.origin = Some(format!("{}[{}]", source, i));
+        let kid = spec
+            .kid
+            .clone()
+            .filter(|kid| !kid.is_empty())
+            .ok_or_else(|| Error::Config(format!("{} is required", spec.field("kid"))))?;
+        if keys.contains_key(&kid) {
+            return Err(Error::Config(format!("{} repeats the kid '{}'", spec.field("kid"), kid)));
+        }
+        keys.insert(kid, SigningKey::load(spec)?);
+    }
+    Ok(keys)
+}
+
+/// Get the key ring, loading it on first use
+pub fn key_ring() -> Result<&'static KeyRing> {
+    KEY_RING.get_or_try_init(KeyRing::from_env)
+}
+
+/// Get the key that signs right now
+pub fn signing_key() -> Result<&'static SigningKey> {
+    let ring = key_ring()?;
+    let index = ring
+        .keys
+        .iter()
+        .position(|key| key.is_active(crate::token::now()))
+        .ok_or_else(|| Error::Config("No key is active, check not_before and retire_at".to_string()))?;
+
+    let mut last_primary = ring.last_primary.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(previous) = last_primary.replace(index).filter(|previous| *previous != index) {
+        info!(
+            "Key {} replaces {} as the signing key",
+            ring.keys[index].kid.as_deref().unwrap_or("-"),
+            ring.keys[previous].kid.as_deref().unwrap_or("-")
+        );
+    }
+    Ok(&ring.keys[index])
+}
+
+/// Get the key that signs right now among those `usable` accepts, e.g. the PASETO ones
+pub fn signing_key_where(usable: impl Fn(&SigningKey) -> bool) -> Result<&'static SigningKey> {
+    let ring = key_ring()?;
+    ring.keys
+        .iter()
+        .find(|key| key.is_active(crate::token::now()) && usable(key))
+        .ok_or_else(|| Error::Config("No suitable key is active, check the key ring".to_string()))
+}
+
+/// Derive the 256-bit PASETO `v4.local` key from an HMAC secret with HKDF-SHA256
+fn derive_local_key(secret: &[u8]) -> Result<[u8; 32]> {
+    use ring::hkdf::{Salt, HKDF_SHA256};
+
+    let mut key = [0u8; 32];
+    Salt::new(HKDF_SHA256, &[])
+        .extract(secret)
+        .expand(&[LOCAL_KEY_INFO], HKDF_SHA256)
+        .and_then(|okm| okm.fill(&mut key))
+        .map_err(|_| Error::Config("Cannot derive the PASETO v4.local key".to_string()))?;
+    Ok(key)
+}
+
+/// Parse a JOSE algorithm name
+pub fn parse_algorithm(name: &str) -> Result<Algorithm> {
+    name.trim()
+        .parse()
+        .map_err(|_| Error::Config(format!("Unsupported JWT algorithm: {}", name)))
+}
+
+/// Whether the algorithm signs with a shared secret
+pub fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+/// Refuse HMAC secrets that are too short or too predictable, unless in dev mode
+fn check_secret_strength(algorithm: Algorithm, secret: &str, source: &str, dev_mode: bool) -> Result<()> {
+    // RFC 7518 §3.2: the key must be at least as long as the hash output
+    let min_len = match algorithm {
+        Algorithm::HS256 => 32,
+        Algorithm::HS384 => 48,
+        _ => 64,
+    };
+    let decoded = decode_secret(secret);
+    let period = shortest_period(&decoded);
+    let entropy = estimated_entropy_bits(&decoded);
+
+    let problem = if secret.len() < min_len {
+        format!("is {} bytes long, {:?} needs at least {}", secret.len(), algorithm, min_len)
+    } else if decoded.len() < MIN_SECRET_BYTES {
+        format!(
+            "encodes {} bytes, at least {} random bytes are needed",
+            decoded.len(),
+            MIN_SECRET_BYTES
+        )
+    } else if period < decoded.len() {
+        format!("repeats the same {} bytes", period)
+    } else if entropy < MIN_SECRET_ENTROPY_BITS {
+        format!(
+            "is too predictable (about {:.0} bits of entropy, at least {:.0} needed)",
+            entropy, MIN_SECRET_ENTROPY_BITS
+        )
+    } else {
+        return Ok(());
+    };
+
+    if dev_mode {
+        warn!("{} {}; accepted because LRAP_DEV_MODE is on", source, problem);
+        return Ok(());
+    }
+    Err(Error::Config(format!(
+        "{} {}; generate one with `openssl rand -base64 64`",
+        source, problem
+    )))
+}
+
+/// Bytes a secret encodes: decoded as hex or base64 when it is either, as is otherwise
+///
+/// Hex and base64 text carries 4 and 6 bits per character, so measuring the characters
+/// would overstate the secret.
+fn decode_secret(secret: &str) -> Vec<u8> {
+    let is_hex = secret.len().is_multiple_of(2) && secret.bytes().all(|b| b.is_ascii_hexdigit());
+    let hex = || {
+        (0..secret.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&secret[i..i + 2], 16))
+            .collect::<std::result::Result<Vec<u8>, _>>()
+            .ok()
+    };
+    let base64 = || {
+        STANDARD
+            .decode(secret)
+            .or_else(|_| URL_SAFE_NO_PAD.decode(secret.trim_end_matches('=')))
+            .ok()
+    };
+
+    is_hex
+        .then(hex)
+        .flatten()
+        .or_else(base64)
+        .unwrap_or_else(|| secret.as_bytes().to_vec())
+}
+
+/// Length of the shortest block `bytes` is made of, repeated; its own length when there is none
+fn shortest_period(bytes: &[u8]) -> usize {
+    (1..=bytes.len() / 2)
+        .find(|&period| bytes.iter().skip(period).zip(bytes).all(|(a, b)| a == b))
+        .unwrap_or(bytes.len())
+}
+
+/// Estimate the entropy of a secret from its byte frequencies (Shannon), in bits
+///
+/// Random bytes score close to their true entropy; repeated bytes and short alphabets
+/// score low. Patterns over distinct bytes, such as `0123…`, still score high.
+fn estimated_entropy_bits(secret: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for byte in secret {
+        counts[*byte as usize] += 1;
+    }
+
+    let len = secret.len() as f64;
+    let per_byte: f64 = counts
+        .iter()
+        .filter(|count| **count > 0)
+        .map(|count| {
+            let p = *count as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    per_byte * len
+}
+
+/// Read a private key as DER from a file or an inline value, along with where it came from
+///
+/// The file wins when both are given. Inline values are PEM text or base64 of the DER bytes.
+pub fn read_private_key(
+    path: Option<&str>,
+    path_field: &str,
+    inline: Option<&str>,
+    inline_field: &str,
+) -> Result<Option<(Vec<u8>, String)>> {
+    if let Some(path) = path {
+        let bytes = std::fs::read(path)
+            .map_err(|e| Error::Config(format!("Cannot read {} {}: {}", path_field, path, e)))?;
+        return Ok(Some((to_der(&bytes, path)?, path.to_owned())));
+    }
+
+    let Some(value) = inline else {
+        return Ok(None);
+    };
+    let bytes = if value.trim_start().starts_with("-----BEGIN") {
+        value.as_bytes().to_vec()
+    } else {
+        STANDARD
+            .decode(value.trim())
+            .map_err(|e| Error::Config(format!("{} is neither PEM nor base64 DER: {}", inline_field, e)))?
+    };
+    Ok(Some((to_der(&bytes, inline_field)?, inline_field.to_owned())))
+}
+
+/// Strip the PEM armor if present, otherwise assume the bytes already are DER
+fn to_der(bytes: &[u8], source: &str) -> Result<Vec<u8>> {
+    if !bytes.trim_ascii_start().starts_with(b"-----BEGIN") {
+        return Ok(bytes.to_vec());
+    }
+
+    let pem = pem::parse(bytes).map_err(|e| Error::Config(format!("Invalid PEM in {}: {}", source, e)))?;
+    match pem.tag() {
+        "PRIVATE KEY" | "RSA PRIVATE KEY" => Ok(pem.into_contents()),
+        "EC PRIVATE KEY" => Err(Error::Config(format!(
+            "{} holds a SEC1 EC key, convert it with `openssl pkcs8 -topk8 -nocrypt`",
+            source
+        ))),
+        tag => Err(Error::Config(format!("{} holds a '{}', expected a private key", source, tag))),
+    }
+}
+
+/// Validate a DER private key for `algorithm` and derive its public half
+///
+/// jsonwebtoken wants PKCS#1 for RSA, so PKCS#8 RSA keys go through its PEM loader.
+fn load_asymmetric(algorithm: Algorithm, der: &[u8]) -> std::result::Result<(EncodingKey, PublicKey), String> {
+    let rejected = |e: ring::error::KeyRejected| e.to_string();
+    let pkcs8_pem = || pem::encode(&pem::Pem::new("PRIVATE KEY", der.to_vec()));
+
+    match algorithm {
+        Algorithm::RS256
+        | Algorithm::RS384
+        | Algorithm::RS512
+        | Algorithm::PS256
+        | Algorithm::PS384
+        | Algorithm::PS512 => {
+            let (key_pair, encoding) = match signature::RsaKeyPair::from_der(der) {
+                Ok(key_pair) => (key_pair, EncodingKey::from_rsa_der(der)),
+                Err(_) => (
+                    signature::RsaKeyPair::from_pkcs8(der).map_err(rejected)?,
+                    EncodingKey::from_rsa_pem(pkcs8_pem().as_bytes()).map_err(|e| e.to_string())?,
+                ),
+            };
+            let components: signature::RsaPublicKeyComponents<Vec<u8>> = key_pair.public().into();
+            Ok((
+                encoding,
+                PublicKey::Rsa {
+                    n: components.n,
+                    e: components.e,
+                },
+            ))
+        }
+        Algorithm::ES256 | Algorithm::ES384 => {
+            let (signing, crv) = if algorithm == Algorithm::ES256 {
+                (&signature::ECDSA_P256_SHA256_FIXED_SIGNING, "P-256")
+            } else {
+                (&signature::ECDSA_P384_SHA384_FIXED_SIGNING, "P-384")
+            };
+            let key_pair = signature::EcdsaKeyPair::from_pkcs8(signing, der, &ring::rand::SystemRandom::new())
+                .map_err(rejected)?;
+
+            // Uncompressed point: 0x04 || x || y
+            let point = &key_pair.public_key().as_ref()[1..];
+            let (x, y) = point.split_at(point.len() / 2);
+            Ok((
+                EncodingKey::from_ec_der(der),
+                PublicKey::Ec {
+                    crv,
+                    x: x.to_vec(),
+                    y: y.to_vec(),
+                },
+            ))
+        }
+        Algorithm::EdDSA => {
+            let key_pair = signature::Ed25519KeyPair::from_pkcs8_maybe_unchecked(der).map_err(rejected)?;
+            Ok((
+                EncodingKey::from_ed_der(der),
+                PublicKey::Okp {
+                    crv: "Ed25519",
+                    x: key_pair.public_key().as_ref().to_vec(),
+                },
+            ))
+        }
+        Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512 => {
+            Err("HMAC algorithms do not use a private key".to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RFC 7638 §3.1
+    const RSA_JWK: &str = r#"{"kty":"RSA","alg":"RS256","kid":"2011-04-29","e":"AQAB",
+        "n":"0vx7agoebGcQSuuPiLJXZptN9nndrQmbXEps2aiAFbWhM78LhWx4cbbfAAtVT86zwu1RK7aPFFxuhDR1L6tSoc_BJECPebWKRXjBZCiFV4n3oknjhMstn64tZ_2W-5JsGY4Hc5n9yBXArwl93lqt7_RN5w6Cf0h4QyQ5v-65YGjQR0_FDW2QvzqY368QQMicAtaSqzs8KJZgnYb9c7d0zgdAZHzu6qMQvRL5hajrn1n91CbOpbISD08qNLyrdkt-bFTWhAI4vMQFh6WeZu0fM4lFd2NcRwr3XPksINHaQ-G_xBniIqbw0Ls1jF44-csFCur-kEgU8awapJzKnqDKgw"}"#;
+    const RSA_THUMBPRINT: &str = "NzbLsXh8uDCcd-6MNwXF4W_7noWXFZAfHkxZsRGC9Xs";
+
+    #[test]
+    fn thumbprint_matches_rfc_7638_example() {
+        let jwk: Jwk = serde_json::from_str(RSA_JWK).unwrap();
+        let public = PublicKey::from_jwk(&jwk).unwrap();
+        assert_eq!(public.thumbprint(), RSA_THUMBPRINT);
+    }
+
+    fn random_bytes(len: usize) -> Vec<u8> {
+        use ring::rand::SecureRandom;
+
+        let mut bytes = vec![0u8; len];
+        ring::rand::SystemRandom::new().fill(&mut bytes).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn secrets_are_measured_decoded() {
+        assert_eq!(decode_secret(&"ab".repeat(32)), vec![0xab; 32]);
+        assert_eq!(decode_secret(&STANDARD.encode([7u8; 32])), vec![7; 32]);
+        assert_eq!(decode_secret(&URL_SAFE_NO_PAD.encode([0xfbu8; 33])), vec![0xfb; 33]);
+        assert_eq!(decode_secret("correct horse battery staple").len(), 28);
+    }
+
+    #[test]
+    fn predictable_secrets_are_refused_outside_dev_mode() {
+        for secret in ["0".repeat(64), "ab".repeat(32), "super_secret".repeat(4)] {
+            let refused = check_secret_strength(Algorithm::HS256, &secret, "JWT_SECRET", false);
+            assert!(matches!(refused, Err(Error::Config(_))), "{}", secret);
+            assert!(check_secret_strength(Algorithm::HS256, &secret, "JWT_SECRET", true).is_ok());
+        }
+    }
+
+    #[test]
+    fn random_secrets_are_accepted() {
+        let bytes = random_bytes(64);
+        let hex: String = bytes[..32].iter().map(|b| format!("{:02x}", b)).collect();
+        for (algorithm, secret) in [
+            (Algorithm::HS256, hex),
+            (Algorithm::HS256, STANDARD.encode(&bytes[..32])),
+            (Algorithm::HS512, STANDARD.encode(&bytes)),
+            (Algorithm::HS512, URL_SAFE_NO_PAD.encode(&bytes)),
+        ] {
+            assert!(check_secret_strength(algorithm, &secret, "JWT_SECRET", false).is_ok(), "{}", secret);
+        }
+    }
+
+    #[test]
+    fn thumbprint_survives_a_jwk_round_trip() {
+        let public = PublicKey::Ec {
+            crv: "P-256",
+            x: vec![1; 32],
+            y: vec![2; 32],
+        };
+        let jwk = public.to_jwk(None, Algorithm::ES256).unwrap();
+        assert_eq!(PublicKey::from_jwk(&jwk).unwrap().thumbprint(), public.thumbprint());
+
+        let public = PublicKey::Okp {
+            crv: "Ed25519",
+            x: vec![3; 32],
+        };
+        let jwk = public.to_jwk(None, Algorithm::EdDSA).unwrap();
+        assert_eq!(PublicKey::from_jwk(&jwk).unwrap().thumbprint(), public.thumbprint());
+    }
+}
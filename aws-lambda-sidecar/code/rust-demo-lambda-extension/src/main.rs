@@ -1,110 +1,250 @@
-use hyper::{Body, Request, Response, Server, Method, StatusCode};
-use hyper::service::{make_service_fn, service_fn};
-use jsonwebtoken::{encode, EncodingKey, Header};
-use serde::{Serialize};
+use hyper::{Body, Request, Response, Method};
 use std::convert::Infallible;
-use log::info;
+use std::io::Write;
+use std::time::{Duration, Instant};
+use log::{debug, error, info, warn};
+use once_cell::sync::OnceCell;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+mod auth;
+mod discovery;
+mod dpop;
 mod env;
+mod error;
 mod extension;
-
-#[derive(Serialize)]
-struct Claims {
-    sub: String,
-    exp: usize,
-}
-
-#[derive(Serialize)]
-struct TokenResponse {
-    token: String,
-    expires_at: usize,
-    user_id: String,
-    message: String,
-}
+mod http;
+mod introspection;
+mod jwe;
+mod keys;
+mod middleware;
+mod oauth;
+mod paseto;
+mod proxy;
+mod refresh;
+mod revocation;
+mod router;
+mod server;
+mod signing;
+mod telemetry;
+mod token;
+mod verify;
+
+use error::{Error, Result};
+use extension::NextEvent;
+use middleware::{AccessLog, AssignRequestId, BodyLimit, CatchPanic, Middleware, ServerTiming, Timeout};
+use router::Router;
 
 pub const EXTENSION_NAME: &str = "rust-demo-lambda-extension";
 pub static LAMBDA_RUNTIME_API_VERSION: &str = "2018-06-01";
 
+/// Time kept in reserve before the SHUTDOWN deadline so the process can exit cleanly
+const SHUTDOWN_MARGIN: Duration = Duration::from_millis(100);
+
 /// Handle the request
 ///
 /// This is the main function that handles the request.
 ///
-/// It handles the request and returns the response.
+/// It hands the request, and the credentials of the caller when the connection has them,
+/// to the [`router`], and renders any error as a structured JSON response.
 ///
-async fn handle_request(req: Request<Body>) -> Result<Response<Body>, Infallible> {
-    match (req.method(), req.uri().path()) {
-        (&Method::GET, "/my-token") => {
-            let claims = Claims {
-                sub: "user123".to_string(),
-                exp: 2000000000, // timestamp
-            };
-
-            let secret = std::env::var("JWT_SECRET").unwrap_or_else(|_| "super_secret".to_string());
-
-            let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(secret.as_ref())).unwrap();
-
-            let response = TokenResponse {
-                token,
-                expires_at: claims.exp,
-                user_id: claims.sub.clone(),
-                message: "JWT token generated successfully".to_string(),
-            };
-
-            let json_response = serde_json::to_string(&response).unwrap();
-
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "application/json")
-                .body(Body::from(json_response))
-                .unwrap())
-        }
-        _ => {
-            let error_response = serde_json::json!({
-                "error": "Endpoint not found",
-                "message": "Use /token to get a JWT",
-                "status": 404
-            });
-
-            Ok(Response::builder()
-                .status(StatusCode::NOT_FOUND)
-                .header("Content-Type", "application/json")
-                .body(Body::from(error_response.to_string()))
-                .unwrap())
-        }
+async fn handle_request(
+    mut req: Request<Body>,
+    peer: Option<auth::Peer>,
+) -> std::result::Result<Response<Body>, Infallible> {
+    if let Some(peer) = peer {
+        req.extensions_mut().insert(peer);
     }
+    let response = match router() {
+        Ok(router) => router.dispatch(req).await,
+        Err(e) => Err(e),
+    };
+    Ok(response.unwrap_or_else(Error::into_response))
 }
 
-#[tokio::main]
-async fn main() {
-    env_logger::init();
-
-   
+static ROUTER: OnceCell<Router> = OnceCell::new();
+
+/// Every endpoint of the local HTTP server, and the middleware around them
+fn router() -> Result<&'static Router> {
+    ROUTER.get_or_try_init(|| {
+        let limits = middleware::limits()?;
+        let form_limit = BodyLimit(oauth::MAX_FORM_BYTES.min(limits.max_body));
+        Ok(Router::new()
+            .with(AssignRequestId)
+            .with(AccessLog)
+            .with(ServerTiming)
+            .with(CatchPanic)
+            .with(auth::authenticate)
+            .with(BodyLimit(limits.max_body))
+            .with(Timeout(limits.timeout))
+            .route("/my-token", &[Method::GET, Method::POST], token::handle_my_token)
+            .route("/oauth/token", &[Method::POST], form_limit.around(oauth::handle_token))
+            .route("/introspect", &[Method::POST], form_limit.around(introspection::handle_introspect))
+            .route("/revoke", &[Method::POST], form_limit.around(revocation::handle_revoke))
+            .route("/verify", &[Method::POST], verify::handle_verify)
+            .route("/sign", &[Method::POST], signing::handle_sign)
+            .route("/verify-signature", &[Method::POST], signing::handle_verify_signature)
+            .route("/.well-known/jwks.json", &[Method::GET, Method::HEAD], discovery::handle_jwks)
+            .route("/.well-known/jwks/{kid}", &[Method::GET, Method::HEAD], discovery::handle_jwk)
+            .route(
+                "/.well-known/openid-configuration",
+                &[Method::GET, Method::HEAD],
+                discovery::handle_openid_configuration,
+            ))
+    })
+}
 
-    let addr = ([127, 0, 0, 1], 8000).into(); // HTTP local
+/// Flush anything buffered in the process before Lambda freezes or kills it
+fn flush_state() {
+    log::logger().flush();
+    let _ = std::io::stdout().flush();
+    let _ = std::io::stderr().flush();
+}
 
-    let make_svc = make_service_fn(|_conn| async {
-        Ok::<_, Infallible>(service_fn(handle_request))
-    });
+/// Extract the message of a panicked task so it can be reported to Lambda
+fn panic_message(error: tokio::task::JoinError) -> String {
+    if !error.is_panic() {
+        return error.to_string();
+    }
 
-    let server = Server::bind(&addr).serve(make_svc);
+    middleware::panic_message(error.into_panic().as_ref())
+}
 
-    info!("Extension HTTP server running on http://{}", addr);
+/// Report a fatal error through the Extensions API and exit the process
+async fn fail(error_type: &str, message: &str) -> ! {
+    error!("{}: {}", error_type, message);
+    if let Err(e) = extension::report_error(error_type, message).await {
+        error!("Cannot report {} to the Extensions API: {}", error_type, e);
+    }
+    flush_state();
+    std::process::exit(1);
+}
 
-     tokio::spawn(async {
-        extension::register().await;
+/// Report an [`Error`] through the Extensions API and exit the process
+async fn fail_with(error: Error) -> ! {
+    fail(error.error_type(), &error.to_string()).await
+}
 
-        loop {
-            // Lambda Extension API requires we wait for next extension event
-            extension::get_next().await;
+/// Follow the Extensions API event loop until SHUTDOWN and return its deadline
+async fn run_events() -> Result<Instant> {
+    loop {
+        // Lambda Extension API requires we wait for next extension event
+        let event = extension::get_next().await?;
+        let deadline = event.deadline();
+
+        match event {
+            NextEvent::Invoke(invoke) => {
+                info!("Received INVOKE event (request id: {})", invoke.request_id);
+                if let Some(tracing) = &invoke.tracing {
+                    debug!("Invocation tracing {}: {}", tracing.kind, tracing.value);
+                }
+                extension::set_current_invoke(invoke);
+            }
+            NextEvent::Shutdown(shutdown) => {
+                info!("Received SHUTDOWN event (reason: {})", shutdown.shutdown_reason);
+                return Ok(deadline);
+            }
         }
-    });
-
-    if let Err(e) = server.await {
-        eprintln!("server error: {}", e);
     }
 }
 
+#[tokio::main]
+async fn main() {
+    env_logger::init();
+
+    // Failures before registration cannot be reported: Lambda requires the
+    // extension identifier on the error endpoints, so we can only log and exit.
+    if let Err(e) = env::latch_runtime_env() {
+        fail_with(e).await;
+    }
+
+    // The proxy must listen before registering, since Lambda starts the runtime
+    // right after; a bind failure is reported once we have an identifier. The same
+    // goes for the caller secret the runtime picks up.
+    let proxy = proxy::bind(Arc::new(proxy::Passthrough));
+    let caller_auth = auth::config();
+
+    if let Err(e) = extension::register().await {
+        fail_with(e).await;
+    }
 
+    let proxy = match proxy {
+        Ok(proxy) => tokio::spawn(proxy),
+        Err(e) => fail_with(e).await,
+    };
+    if let Err(e) = caller_auth {
+        fail_with(e).await;
+    }
 
+    // Surface a bad token configuration at init rather than on the first request
+    if let Err(e) = refresh::install(Arc::new(refresh::MemoryStore::default()))
+        .and(token::config())
+        .and(keys::signing_key())
+        .and(jwe::encryption_key())
+        .and(verify::config())
+        .and(dpop::max_age())
+        .and(oauth::registry())
+        .and(refresh::ttl())
+        .and(revocation::denylist())
+        .and(signing::keys())
+        .and(signing::tolerance())
+        .and(router())
+    {
+        fail_with(e).await;
+    }
 
+    let telemetry = match telemetry::start().await {
+        Ok(telemetry) => telemetry,
+        Err(e) => fail_with(e).await,
+    };
+
+    let (drain_tx, drain_rx) = oneshot::channel::<()>();
+
+    let mut server = match server::bind(async {
+        let _ = drain_rx.await;
+    }) {
+        Ok(server) => server,
+        Err(e) => fail_with(e).await,
+    };
+
+    let events = tokio::spawn(run_events());
+
+    let deadline = tokio::select! {
+        result = &mut server => match result {
+            Ok(()) => fail("Extension.ServerError", "HTTP server stopped unexpectedly").await,
+            Err(e) => fail_with(Error::Server(e)).await,
+        },
+        result = proxy => match result {
+            Ok(Ok(())) => fail("Extension.ServerError", "Runtime API proxy stopped unexpectedly").await,
+            Ok(Err(e)) => fail_with(e).await,
+            Err(e) => fail("Extension.Crash", &panic_message(e)).await,
+        },
+        result = events => match result {
+            Ok(Ok(deadline)) => deadline,
+            Ok(Err(e)) => fail_with(e).await,
+            Err(e) => fail("Extension.Crash", &panic_message(e)).await,
+        },
+    };
+
+    // Stop accepting connections and let in-flight requests finish before the deadline
+    let _ = drain_tx.send(());
+    let grace = deadline
+        .checked_sub(SHUTDOWN_MARGIN)
+        .unwrap_or(deadline);
+
+    let drained = async {
+        match tokio::time::timeout_at(grace.into(), server).await {
+            Ok(Ok(())) => info!("HTTP server drained"),
+            Ok(Err(e)) => fail_with(Error::Server(e)).await,
+            Err(_) => warn!("Shutdown deadline reached with requests still in flight"),
+        }
+    };
+    let flushed = async {
+        if let Some(telemetry) = telemetry {
+            telemetry.flush(grace).await;
+        }
+    };
+    tokio::join!(drained, flushed);
 
- 
+    server::cleanup();
+    flush_state();
+}
@@ -0,0 +1,302 @@
+//! Middleware around the sidecar's endpoints
+//!
+//! A [`Middleware`] sees every request before its handler and every result after it, and
+//! decides whether to call the rest of the chain at all. Middleware added with
+//! [`crate::router::Router::with`] wraps every request, 404s and 405s included, in the
+//! order it was added. [`Middleware::around`] wraps a single route's handler:
+//!
+//! ```ignore
+//! .route("/introspect", &[Method::POST], Timeout(Duration::from_secs(1)).around(handle_introspect))
+//! ```
+//!
+//! A route's own [`Timeout`] or [`BodyLimit`] can only be tighter than the global one,
+//! which runs first. The built-in limits come from the environment:
+//!
+//! | Variable                    | Default                                   |
+//! |-----------------------------|-------------------------------------------|
+//! | `LRAP_HTTP_MAX_BODY_BYTES`  | `1048576` (1 MiB)                         |
+//! | `LRAP_HTTP_TIMEOUT_SECONDS` | `10`                                      |
+//!
+
+use crate::env::parsed_var;
+use crate::error::{Error, Result};
+use crate::router::{Handler, HandlerFuture};
+use hyper::body::HttpBody;
+use hyper::header::{HeaderName, HeaderValue, CONTENT_LENGTH};
+use hyper::{Body, Request, Response};
+use log::info;
+use once_cell::sync::OnceCell;
+use std::any::Any;
+use std::future::Future;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Header carrying the request id, taken from the caller when it sends one
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Longest caller-chosen request id we keep
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+static LIMITS: OnceCell<Limits> = OnceCell::new();
+
+/// Limits applied to every request
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    pub max_body: usize,
+    pub timeout: Duration,
+}
+
+impl Limits {
+    fn from_env() -> Result<Self> {
+        Ok(Limits {
+            max_body: parsed_var("LRAP_HTTP_MAX_BODY_BYTES", 1024 * 1024)?,
+            timeout: Duration::from_secs(parsed_var("LRAP_HTTP_TIMEOUT_SECONDS", 10)?),
+        })
+    }
+}
+
+/// Get the request limits, reading them on first use
+pub fn limits() -> Result<&'static Limits> {
+    LIMITS.get_or_try_init(Limits::from_env)
+}
+
+/// Runs around a handler, and every middleware after it in the chain
+///
+/// Implemented for every `async fn(Request<Body>, Next) -> Result<Response<Body>>`.
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture;
+
+    /// Wrap a single handler, for middleware only one route needs
+    fn around(self, handler: impl Handler) -> Around
+    where
+        Self: Sized,
+    {
+        Around {
+            middleware: Box::new(self),
+            handler: Arc::new(handler),
+        }
+    }
+}
+
+impl<F, Fut> Middleware for F
+where
+    F: Fn(Request<Body>, Next) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<Body>>> + Send + 'static,
+{
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        Box::pin(self(req, next))
+    }
+}
+
+/// The rest of the chain, down to the handler
+pub struct Next {
+    chain: &'static [Box<dyn Middleware>],
+    handler: Arc<dyn Handler>,
+}
+
+impl Next {
+    pub fn new(chain: &'static [Box<dyn Middleware>], handler: Arc<dyn Handler>) -> Self {
+        Next { chain, handler }
+    }
+
+    /// Hand the request to the next middleware, or the handler after the last one
+    pub fn run(self, req: Request<Body>) -> HandlerFuture {
+        match self.chain.split_first() {
+            Some((middleware, chain)) => middleware.call(
+                req,
+                Next {
+                    chain,
+                    handler: self.handler,
+                },
+            ),
+            None => self.handler.call(req),
+        }
+    }
+}
+
+/// A handler wrapped in middleware of its own, see [`Middleware::around`]
+pub struct Around {
+    middleware: Box<dyn Middleware>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Handler for Around {
+    fn call(&self, req: Request<Body>) -> HandlerFuture {
+        self.middleware.call(req, Next::new(&[], self.handler.clone()))
+    }
+}
+
+/// Id of the request, kept in the request extensions
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Give every request an id, echoed in `X-Request-Id`
+///
+/// Callers may pick the id, so a request can be followed from the function's logs into
+/// ours; otherwise a UUID is generated. Errors are rendered here so their responses carry
+/// the id as well.
+pub struct AssignRequestId;
+
+impl Middleware for AssignRequestId {
+    fn call(&self, mut req: Request<Body>, next: Next) -> HandlerFuture {
+        let id = req
+            .headers()
+            .get(REQUEST_ID_HEADER)
+            .and_then(|v| v.to_str().ok())
+            .filter(|v| !v.is_empty() && v.len() <= MAX_REQUEST_ID_LEN)
+            .map(str::to_owned)
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestId(id.clone()));
+
+        Box::pin(async move {
+            let mut response = next.run(req).await.unwrap_or_else(Error::into_response);
+            if let Ok(value) = HeaderValue::from_str(&id) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static(REQUEST_ID_HEADER), value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Log one line per request: method, path, status, duration and request id
+pub struct AccessLog;
+
+impl Middleware for AccessLog {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        let method = req.method().clone();
+        let path = req.uri().path().to_owned();
+        let id = req
+            .extensions()
+            .get::<RequestId>()
+            .map(|id| id.0.clone())
+            .unwrap_or_else(|| "-".to_string());
+        let started = Instant::now();
+
+        Box::pin(async move {
+            let result = next.run(req).await;
+            let status = match &result {
+                Ok(response) => response.status(),
+                Err(e) => e.status(),
+            };
+            info!(
+                "{} {} {} {:.1}ms request_id={}",
+                method,
+                path,
+                status.as_u16(),
+                started.elapsed().as_secs_f64() * 1000.0,
+                id
+            );
+            result
+        })
+    }
+}
+
+/// Report the time spent on the request in `Server-Timing`
+pub struct ServerTiming;
+
+impl Middleware for ServerTiming {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        let started = Instant::now();
+        Box::pin(async move {
+            let mut response = next.run(req).await.unwrap_or_else(Error::into_response);
+            let timing = format!("app;dur={:.1}", started.elapsed().as_secs_f64() * 1000.0);
+            if let Ok(value) = HeaderValue::from_str(&timing) {
+                response
+                    .headers_mut()
+                    .insert(HeaderName::from_static("server-timing"), value);
+            }
+            Ok(response)
+        })
+    }
+}
+
+/// Extract the message a panic was raised with
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "panicked without a message".to_string())
+}
+
+/// Polls a future, turning a panic into an error
+struct CatchUnwind(HandlerFuture);
+
+impl Future for CatchUnwind {
+    type Output = Result<Response<Body>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // The future is dropped right after a panic, so no broken state is observed
+        match std::panic::catch_unwind(AssertUnwindSafe(|| self.0.as_mut().poll(cx))) {
+            Ok(poll) => poll,
+            Err(payload) => Poll::Ready(Err(Error::Panic(panic_message(payload.as_ref())))),
+        }
+    }
+}
+
+/// Answer a panicking handler with a 500 instead of dropping the connection
+pub struct CatchPanic;
+
+impl Middleware for CatchPanic {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        Box::pin(CatchUnwind(next.run(req)))
+    }
+}
+
+/// Refuse request bodies over the given number of bytes with a 413
+///
+/// The body is read here, so handlers get it whole and never read more than the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimit(pub usize);
+
+impl Middleware for BodyLimit {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        let limit = self.0;
+        Box::pin(async move {
+            let too_large =
+                || Error::PayloadTooLarge(format!("request bodies are limited to {} bytes", limit));
+
+            let declared = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            if declared.is_some_and(|length| length > limit as u64) {
+                return Err(too_large());
+            }
+
+            let (parts, mut body) = req.into_parts();
+            let mut buffer = Vec::new();
+            while let Some(chunk) = body.data().await {
+                let chunk =
+                    chunk.map_err(|e| Error::BadRequest(format!("Cannot read the request body: {}", e)))?;
+                if buffer.len() + chunk.len() > limit {
+                    return Err(too_large());
+                }
+                buffer.extend_from_slice(&chunk);
+            }
+
+            next.run(Request::from_parts(parts, Body::from(buffer))).await
+        })
+    }
+}
+
+/// Answer with a 503 when the rest of the chain takes longer than the given duration
+#[derive(Debug, Clone, Copy)]
+pub struct Timeout(pub Duration);
+
+impl Middleware for Timeout {
+    fn call(&self, req: Request<Body>, next: Next) -> HandlerFuture {
+        let timeout = self.0;
+        Box::pin(async move {
+            tokio::time::timeout(timeout, next.run(req))
+                .await
+                .unwrap_or_else(|_| Err(Error::Timeout(format!("no response within {:?}", timeout))))
+        })
+    }
+}
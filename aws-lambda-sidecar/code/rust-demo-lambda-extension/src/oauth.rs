@@ -0,0 +1,712 @@
+//! OAuth 2.0 token endpoint (RFC 6749)
+//!
+//! `POST /oauth/token` issues access tokens to registered clients with the
+//! `client_credentials` grant (§4.4), renews them with the `refresh_token` grant (§6), and
+//! trades a caller's token for a narrower one with token exchange (RFC 8693).
+//! Clients authenticate with HTTP Basic or with the
+//! `client_id` and `client_secret` form fields (§2.3.1), never both. Basic credentials are
+//! form encoded before base64, so a `+` in a secret is sent as `%2B`.
+//!
+//! | Variable             | Meaning                                                   |
+//! |----------------------|-----------------------------------------------------------|
+//! | `OAUTH_CLIENTS`      | client registry as inline JSON                            |
+//! | `OAUTH_CLIENTS_PATH` | client registry as a JSON file, e.g. `/opt/keys/clients.json` |
+//!
+//! ```json
+//! [
+//!   { "client_id": "orders", "client_secret_env": "ORDERS_CLIENT_SECRET",
+//!     "scopes": ["orders:read", "orders:write"], "audiences": ["https://orders.example.com"],
+//!     "ttl_seconds": 900,
+//!     "grant_types": ["client_credentials", "urn:ietf:params:oauth:grant-type:token-exchange"],
+//!     "exchange": { "audiences": ["https://inventory.example.com"],
+//!                   "scopes": ["inventory:read"], "max_ttl_seconds": 300 } }
+//! ]
+//! ```
+//!
+//! A request without `scope` is granted every scope of the client, and one without
+//! `audience` every audience (or `JWT_AUDIENCE` when the client lists none). Asking for
+//! more fails with `invalid_scope` or `invalid_target`. Tokens carry the client id as `sub`
+//! and `client_id`, and the granted scopes in `scope`, space separated (RFC 9068 §2.2).
+//!
+//! Clients get JWTs unless their `token_format` is `v4.public` or `v4.local` (see
+//! [`crate::paseto`]); tokens renewed or exchanged keep that format.
+//!
+//! Only clients listing `refresh_token` in `grant_types` get a refresh token with their
+//! access token. Refresh tokens from `/my-token` are redeemed here without client
+//! authentication; those issued to a client need that client's credentials. A refresh
+//! request may narrow the granted scopes with `scope`; an empty `scope` fails with
+//! `invalid_scope` rather than silently granting them all.
+//!
+//! Token exchange lets a client call another service on behalf of the user whose token it
+//! holds. The `subject_token` must pass the `/verify` checks; the new token keeps its `sub`
+//! and custom claims, is addressed to one of the `audiences` of the client's `exchange`
+//! policy, and carries the scopes both the subject token and the policy have (or the
+//! requested subset). It lives no longer than `max_ttl_seconds`, `JWT_TTL_SECONDS` and the
+//! subject token, and names the client in `act` (RFC 8693 §4.1), nesting any earlier actor.
+//! An encrypted subject token is exchanged for an encrypted one.
+//!
+//! A `DPoP` proof on any grant binds the access token to the proof's key, with
+//! `token_type` `DPoP` (see [`crate::dpop`]). Refresh tokens bound at `/my-token` are only
+//! redeemed with a proof from the same key. A bound subject token is only exchanged with a
+//! proof from its key, and the exchanged token stays bound to it.
+//!
+
+use crate::error::{Error, Result};
+use crate::http::form_body;
+use crate::refresh::{self, Grant};
+use crate::token::{Claims, TokenConfig, TokenFormat};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use hyper::header::{HeaderValue, AUTHORIZATION, CACHE_CONTROL, PRAGMA};
+use hyper::{Body, Request, Response, StatusCode};
+use once_cell::sync::OnceCell;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// Grant type of token exchange (RFC 8693 §2.1)
+const TOKEN_EXCHANGE: &str = "urn:ietf:params:oauth:grant-type:token-exchange";
+
+/// Token types accepted as `subject_token_type` and `requested_token_type` (RFC 8693 §3)
+const ACCESS_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:access_token";
+const JWT_TOKEN_TYPE: &str = "urn:ietf:params:oauth:token-type:jwt";
+
+/// Largest form body the token, introspection and revocation endpoints read; the biggest
+/// field is a token, a few KiB at most
+pub const MAX_FORM_BYTES: usize = 16 * 1024;
+
+static REGISTRY: OnceCell<Registry> = OnceCell::new();
+
+/// One client as described in `OAUTH_CLIENTS`
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClientSpec {
+    client_id: String,
+    client_secret: Option<String>,
+    client_secret_env: Option<String>,
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    audiences: Vec<String>,
+    #[serde(default = "default_grant_types")]
+    grant_types: Vec<String>,
+    ttl_seconds: Option<u64>,
+    #[serde(default)]
+    token_format: TokenFormat,
+    exchange: Option<ExchangePolicy>,
+}
+
+fn default_grant_types() -> Vec<String> {
+    vec!["client_credentials".to_string()]
+}
+
+/// A registered client and what it may ask for
+#[derive(Debug)]
+pub struct Client {
+    pub id: String,
+    secret: String,
+    pub scopes: Vec<String>,
+    pub audiences: Vec<String>,
+    pub grant_types: Vec<String>,
+    pub ttl: Option<u64>,
+    pub token_format: TokenFormat,
+    pub exchange: Option<ExchangePolicy>,
+}
+
+/// What a client may get for the tokens it exchanges
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangePolicy {
+    pub audiences: Vec<String>,
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    #[serde(rename = "max_ttl_seconds")]
+    pub max_ttl: Option<u64>,
+}
+
+/// Every client allowed to call the token endpoint
+#[derive(Debug, Default)]
+pub struct Registry {
+    clients: HashMap<String, Client>,
+}
+
+impl Registry {
+    /// Load the registry from `OAUTH_CLIENTS` / `OAUTH_CLIENTS_PATH`, empty when neither is set
+    fn from_env() -> Result<Self> {
+        let registry = crate::env::json_var::<Vec<ClientSpec>>("OAUTH_CLIENTS", "OAUTH_CLIENTS_PATH")?;
+        let Some((specs, source)) = registry else {
+            return Ok(Registry::default());
+        };
+
+        let mut clients = HashMap::new();
+        for (i, spec) in specs.into_iter().enumerate() {
+            let origin = format!("{}[{}]", source, i);
+            let secret = match (spec.client_secret, &spec.client_secret_env) {
+                (Some(secret), None) => secret,
+                (None, Some(name)) => crate::env::optional_var(name)?.ok_or_else(|| {
+                    Error::Config(format!("{}.client_secret_env names {}, which is not set", origin, name))
+                })?,
+                _ => {
+                    return Err(Error::Config(format!(
+                        "{} needs exactly one of client_secret or client_secret_env",
+                        origin
+                    )))
+                }
+            };
+            if secret.is_empty() {
+                return Err(Error::Config(format!("{} has an empty client secret", origin)));
+            }
+            if spec.ttl_seconds == Some(0) {
+                return Err(Error::Config(format!("{}.ttl_seconds must be greater than zero", origin)));
+            }
+            spec.token_format.check_key()?;
+            match (&spec.exchange, spec.grant_types.iter().any(|grant| grant == TOKEN_EXCHANGE)) {
+                (Some(policy), true) => {
+                    if policy.audiences.is_empty() {
+                        return Err(Error::Config(format!("{}.exchange.audiences is empty", origin)));
+                    }
+                    if policy.max_ttl == Some(0) {
+                        return Err(Error::Config(format!(
+                            "{}.exchange.max_ttl_seconds must be greater than zero",
+                            origin
+                        )));
+                    }
+                }
+                (None, false) => {}
+                (Some(_), false) => {
+                    return Err(Error::Config(format!(
+                        "{} has an exchange policy but does not list the {} grant",
+                        origin, TOKEN_EXCHANGE
+                    )))
+                }
+                (None, true) => {
+                    return Err(Error::Config(format!(
+                        "{} lists the token exchange grant but has no exchange policy",
+                        origin
+                    )))
+                }
+            }
+
+            let client = Client {
+                id: spec.client_id,
+                secret,
+                scopes: spec.scopes,
+                audiences: spec.audiences,
+                grant_types: spec.grant_types,
+                ttl: spec.ttl_seconds,
+                token_format: spec.token_format,
+                exchange: spec.exchange,
+            };
+            if clients.contains_key(&client.id) {
+                return Err(Error::Config(format!("{} repeats the client_id '{}'", origin, client.id)));
+            }
+            clients.insert(client.id.clone(), client);
+        }
+
+        Ok(Registry { clients })
+    }
+
+    /// The client these credentials belong to
+    fn authenticate(&self, id: &str, secret: &str) -> Result<&Client> {
+        self.clients
+            .get(id)
+            .filter(|client| secrets_match(secret, &client.secret))
+            .ok_or_else(|| Error::oauth("invalid_client", "Unknown client or wrong client secret"))
+    }
+}
+
+/// Get the client registry, loading it on first use
+pub fn registry() -> Result<&'static Registry> {
+    REGISTRY.get_or_try_init(Registry::from_env)
+}
+
+/// Compare secrets through their digests, so timing says nothing about the stored one
+pub fn secrets_match(presented: &str, expected: &str) -> bool {
+    let digest = |secret: &str| ring::digest::digest(&ring::digest::SHA256, secret.as_bytes());
+    digest(presented).as_ref() == digest(expected).as_ref()
+}
+
+/// Form parameters of a token request; unknown ones are ignored (§3.2)
+#[derive(Debug, Default, Deserialize)]
+struct TokenRequest {
+    grant_type: Option<String>,
+    scope: Option<String>,
+    audience: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    refresh_token: Option<String>,
+    resource: Option<String>,
+    subject_token: Option<String>,
+    subject_token_type: Option<String>,
+    requested_token_type: Option<String>,
+    actor_token: Option<String>,
+}
+
+/// Successful token response (§5.1)
+#[derive(Serialize)]
+struct AccessTokenResponse {
+    access_token: String,
+    /// Only for token exchange (RFC 8693 §2.2.1)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    issued_token_type: Option<&'static str>,
+    token_type: &'static str,
+    expires_in: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    scope: Option<String>,
+}
+
+/// Read the form body of an OAuth request, reporting a bad body as `invalid_request`
+pub async fn oauth_form<T: DeserializeOwned>(req: Request<Body>) -> Result<T> {
+    form_body(req).await.map_err(|e| match e {
+        Error::BadRequest(message) => Error::oauth("invalid_request", message),
+        e => e,
+    })
+}
+
+/// Decode a `client_id` or `client_secret` taken from Basic credentials (§2.3.1)
+fn form_decode(value: &str) -> Result<String> {
+    percent_encoding::percent_decode_str(&value.replace('+', " "))
+        .decode_utf8()
+        .map(|value| value.into_owned())
+        .map_err(|_| Error::oauth("invalid_request", "Client credentials are not valid UTF-8"))
+}
+
+/// Client id and secret from `Authorization: Basic`, if present
+pub fn basic_credentials(req: &Request<Body>) -> Result<Option<(String, String)>> {
+    let Some(header) = req.headers().get(AUTHORIZATION) else {
+        return Ok(None);
+    };
+    let malformed = || Error::oauth("invalid_request", "Malformed Basic authorization header");
+
+    let header = header.to_str().map_err(|_| malformed())?;
+    let (scheme, credentials) = header.split_once(' ').ok_or_else(malformed)?;
+    if !scheme.eq_ignore_ascii_case("Basic") {
+        return Err(Error::oauth("invalid_request", "Only Basic client authentication is supported"));
+    }
+
+    let decoded = STANDARD.decode(credentials.trim()).map_err(|_| malformed())?;
+    let decoded = String::from_utf8(decoded).map_err(|_| malformed())?;
+    let (id, secret) = decoded.split_once(':').ok_or_else(malformed)?;
+
+    Ok(Some((form_decode(id)?, form_decode(secret)?)))
+}
+
+/// Authenticate the caller with at most one of Basic or the `client_id` / `client_secret` fields
+pub fn authenticate(
+    basic: Option<(String, String)>,
+    client_id: Option<&str>,
+    client_secret: Option<&str>,
+) -> Result<Option<&'static Client>> {
+    let (id, secret) = match (basic, client_secret) {
+        (Some(_), Some(_)) => {
+            return Err(Error::oauth(
+                "invalid_request",
+                "Use one client authentication method, not both",
+            ))
+        }
+        (Some((id, secret)), None) => {
+            if client_id.is_some_and(|form_id| form_id != id) {
+                return Err(Error::oauth("invalid_request", "client_id does not match the Basic credentials"));
+            }
+            (id, secret)
+        }
+        (None, Some(secret)) => {
+            let id = client_id.ok_or_else(|| Error::oauth("invalid_request", "Missing client_id"))?;
+            (id.to_owned(), secret.to_owned())
+        }
+        (None, None) => return Ok(None),
+    };
+
+    registry()?.authenticate(&id, &secret).map(Some)
+}
+
+/// Refuse grants the client is not registered for
+fn check_grant_type(client: &Client, grant_type: &str) -> Result<()> {
+    if client.grant_types.iter().any(|allowed| allowed == grant_type) {
+        return Ok(());
+    }
+    Err(Error::oauth(
+        "unauthorized_client",
+        format!("Client may not use the '{}' grant", grant_type),
+    ))
+}
+
+/// Space separated values of a parameter, `None` when absent
+fn space_list(value: &Option<String>) -> Option<Vec<String>> {
+    value
+        .as_ref()
+        .map(|value| value.split_whitespace().map(str::to_owned).collect())
+}
+
+/// The requested subset of `allowed`, or all of it when nothing was requested
+fn narrow(
+    requested: Option<Vec<String>>,
+    allowed: &[String],
+    code: &'static str,
+    what: &str,
+) -> Result<Vec<String>> {
+    let Some(requested) = requested else {
+        return Ok(allowed.to_vec());
+    };
+    if let Some(extra) = requested.iter().find(|value| !allowed.contains(value)) {
+        return Err(Error::oauth(code, format!("{} '{}' is not allowed for this client", what, extra)));
+    }
+    Ok(requested)
+}
+
+/// Access token claims for `client`, limited to what it asked for
+fn client_claims(client: &Client, form: &TokenRequest, config: &TokenConfig) -> Result<Claims> {
+    let scopes = narrow(space_list(&form.scope), &client.scopes, "invalid_scope", "Scope")?;
+    let allowed_audiences = if client.audiences.is_empty() {
+        &config.audience
+    } else {
+        &client.audiences
+    };
+    let audience = narrow(space_list(&form.audience), allowed_audiences, "invalid_target", "Audience")?;
+
+    let mut claims = Claims::new(client.id.clone(), config);
+    claims.aud = audience;
+    if let Some(ttl) = client.ttl {
+        claims.exp = claims.iat + ttl;
+    }
+    claims.extra.insert("client_id".to_string(), Value::String(client.id.clone()));
+    if !scopes.is_empty() {
+        claims.extra.insert("scope".to_string(), Value::String(scopes.join(" ")));
+    }
+    Ok(claims)
+}
+
+/// Bind `claims` to the DPoP key the caller proved it holds, if any
+fn confirm(claims: &mut Claims, jkt: Option<&str>) {
+    if let Some(jkt) = jkt {
+        crate::dpop::confirm(&mut claims.extra, jkt);
+    }
+}
+
+/// Answer with the access token issued for `claims`, and a refresh token for `grant` when given
+///
+/// Token responses must never be cached (§5.1).
+async fn token_response(
+    claims: Claims,
+    access_token: String,
+    grant: Option<Grant>,
+    issued_token_type: Option<&'static str>,
+) -> Result<Response<Body>> {
+    let refresh_token = match grant {
+        Some(grant) => refresh::issue(grant).await?,
+        None => None,
+    };
+
+    let mut response = crate::http::json_response(
+        StatusCode::OK,
+        &AccessTokenResponse {
+            access_token,
+            issued_token_type,
+            token_type: match crate::dpop::bound_key(&claims.extra) {
+                Some(_) => "DPoP",
+                None => "Bearer",
+            },
+            expires_in: claims.exp - claims.iat,
+            refresh_token,
+            scope: claims.extra.get("scope").and_then(Value::as_str).map(str::to_owned),
+        },
+    )?;
+    let headers = response.headers_mut();
+    headers.insert(CACHE_CONTROL, HeaderValue::from_static("no-store"));
+    headers.insert(PRAGMA, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+/// `grant_type=client_credentials` (§4.4)
+async fn client_credentials(
+    client: Option<&Client>,
+    form: &TokenRequest,
+    config: &TokenConfig,
+    jkt: Option<&str>,
+) -> Result<Response<Body>> {
+    let client = client.ok_or_else(|| Error::oauth("invalid_client", "Client authentication is required"))?;
+    check_grant_type(client, "client_credentials")?;
+
+    let mut claims = client_claims(client, form, config)?;
+    let grant = check_grant_type(client, "refresh_token").is_ok().then(|| {
+        let mut grant = Grant::new(
+            claims.sub.clone(),
+            Some(client.id.clone()),
+            claims.aud.clone(),
+            claims.extra.clone(),
+        );
+        grant.format = client.token_format;
+        grant
+    });
+    confirm(&mut claims, jkt);
+    let access_token = crate::token::issue(&claims, client.token_format, false)?;
+    token_response(claims, access_token, grant, None).await
+}
+
+/// `grant_type=refresh_token` (§6), rotating the presented token
+async fn refresh_token(
+    client: Option<&Client>,
+    form: &TokenRequest,
+    config: &TokenConfig,
+    jkt: Option<&str>,
+) -> Result<Response<Body>> {
+    if let Some(client) = client {
+        check_grant_type(client, "refresh_token")?;
+    }
+    let token = form
+        .refresh_token
+        .as_deref()
+        .ok_or_else(|| Error::oauth("invalid_request", "Missing refresh_token"))?;
+
+    // Check the binding before redeeming, so a proof from the wrong key does not use the token up
+    if let Some(bound) = refresh::lookup(token).await?.and_then(|grant| grant.jkt) {
+        if jkt != Some(bound.as_str()) {
+            return Err(Error::oauth(
+                "invalid_dpop_proof",
+                "Refresh token is bound to a DPoP key; send a proof signed with it",
+            ));
+        }
+    }
+    let grant = refresh::redeem(token, client.map(|client| client.id.as_str())).await?;
+
+    let scopes = refreshed_scopes(&form.scope, &grant.claims)?;
+
+    let mut claims = Claims::new(grant.subject.clone(), config);
+    claims.aud = grant.audience.clone();
+    claims.extra = grant.claims.clone();
+    if !scopes.is_empty() {
+        claims.extra.insert("scope".to_string(), Value::String(scopes.join(" ")));
+    }
+    if let Some(ttl) = client.and_then(|client| client.ttl) {
+        claims.exp = claims.iat + ttl;
+    }
+    confirm(&mut claims, jkt);
+    let access_token = crate::token::issue(&claims, grant.format, grant.encrypted)?;
+    token_response(claims, access_token, Some(grant), None).await
+}
+
+/// Scopes of a refreshed access token: those requested, which may narrow the granted ones
+/// but never widen them, or all granted ones when `scope` is left out
+fn refreshed_scopes(requested: &Option<String>, granted: &Map<String, Value>) -> Result<Vec<String>> {
+    let original: Vec<String> = granted
+        .get("scope")
+        .and_then(Value::as_str)
+        .map(|scope| scope.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default();
+    let requested = space_list(requested);
+    // Asking for nothing must not fall back to everything
+    if requested.as_ref().is_some_and(Vec::is_empty) {
+        return Err(Error::oauth("invalid_scope", "scope is empty; leave it out to keep the granted scopes"));
+    }
+    narrow(requested, &original, "invalid_scope", "Scope")
+}
+
+/// Claims of the subject token that must not carry over to an exchanged token
+const NOT_EXCHANGED: [&str; 3] = ["scope", "client_id", "act"];
+
+/// `grant_type=urn:ietf:params:oauth:grant-type:token-exchange` (RFC 8693 §2)
+async fn token_exchange(
+    client: Option<&Client>,
+    form: &TokenRequest,
+    config: &TokenConfig,
+    jkt: Option<&str>,
+) -> Result<Response<Body>> {
+    let client = client.ok_or_else(|| Error::oauth("invalid_client", "Client authentication is required"))?;
+    check_grant_type(client, TOKEN_EXCHANGE)?;
+    let policy = client
+        .exchange
+        .as_ref()
+        .ok_or_else(|| Error::oauth("unauthorized_client", "Client has no exchange policy"))?;
+
+    if form.actor_token.is_some() {
+        return Err(Error::oauth("invalid_request", "actor_token is not supported"));
+    }
+    let subject_token = form
+        .subject_token
+        .as_deref()
+        .ok_or_else(|| Error::oauth("invalid_request", "Missing subject_token"))?;
+    match form.subject_token_type.as_deref() {
+        Some(ACCESS_TOKEN_TYPE | JWT_TOKEN_TYPE) => {}
+        Some(other) => {
+            return Err(Error::oauth(
+                "invalid_request",
+                format!("Unsupported subject_token_type '{}'", other),
+            ))
+        }
+        None => return Err(Error::oauth("invalid_request", "Missing subject_token_type")),
+    }
+    let issued_token_type = match form.requested_token_type.as_deref() {
+        None | Some(ACCESS_TOKEN_TYPE) => ACCESS_TOKEN_TYPE,
+        Some(JWT_TOKEN_TYPE) if client.token_format == TokenFormat::Jwt => JWT_TOKEN_TYPE,
+        Some(JWT_TOKEN_TYPE) => {
+            return Err(Error::oauth("invalid_request", "Client is issued PASETO tokens, not JWTs"))
+        }
+        Some(other) => {
+            return Err(Error::oauth(
+                "invalid_request",
+                format!("Cannot issue tokens of type '{}'", other),
+            ))
+        }
+    };
+
+    let (subject, encrypted) = match crate::verify::validate(subject_token) {
+        Ok(verified) => (verified.claims, verified.encrypted),
+        Err(e) if e.is_token_rejection() => {
+            return Err(Error::oauth("invalid_request", format!("subject_token is not valid: {}", e)))
+        }
+        Err(e) => return Err(e),
+    };
+    let subject_sub = subject
+        .get("sub")
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::oauth("invalid_request", "subject_token has no sub"))?;
+    let subject_exp = subject.get("exp").and_then(Value::as_u64);
+
+    // Whoever holds a stolen bound token must not be able to trade it for a bearer one
+    if let Some(bound) = crate::dpop::bound_key(&subject) {
+        if jkt != Some(bound) {
+            return Err(Error::oauth(
+                "invalid_dpop_proof",
+                "subject_token is DPoP-bound; send a proof made with its key",
+            ));
+        }
+    }
+
+    // `resource` and `audience` both name the target service (RFC 8693 §2.1)
+    let requested_audience = match (space_list(&form.audience), &form.resource) {
+        (None, None) => None,
+        (audience, resource) => Some(audience.into_iter().flatten().chain(resource.clone()).collect()),
+    };
+    let audience = narrow(requested_audience, &policy.audiences, "invalid_target", "Audience")?;
+
+    let subject_scopes: Vec<String> = subject
+        .get("scope")
+        .and_then(Value::as_str)
+        .map(|scope| scope.split_whitespace().map(str::to_owned).collect())
+        .unwrap_or_default();
+    let exchangeable: Vec<String> = subject_scopes
+        .into_iter()
+        .filter(|scope| policy.scopes.contains(scope))
+        .collect();
+    let scopes = narrow(space_list(&form.scope), &exchangeable, "invalid_scope", "Scope")?;
+
+    let mut claims = Claims::new(subject_sub.to_owned(), config);
+    claims.aud = audience;
+    if let Some(max_ttl) = policy.max_ttl {
+        claims.exp = claims.exp.min(claims.iat + max_ttl);
+    }
+    if let Some(subject_exp) = subject_exp {
+        claims.exp = claims.exp.min(subject_exp);
+    }
+    if claims.exp <= claims.iat {
+        return Err(Error::oauth("invalid_request", "subject_token is about to expire"));
+    }
+
+    claims.extra = subject
+        .iter()
+        .filter(|(name, _)| {
+            !crate::token::REGISTERED_CLAIMS.contains(&name.as_str())
+                && !NOT_EXCHANGED.contains(&name.as_str())
+        })
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect();
+    claims.extra.insert("client_id".to_string(), Value::String(client.id.clone()));
+    if !scopes.is_empty() {
+        claims.extra.insert("scope".to_string(), Value::String(scopes.join(" ")));
+    }
+
+    // The current actor comes first, with the chain of earlier ones nested inside
+    let mut act = Map::new();
+    act.insert("sub".to_string(), Value::String(client.id.clone()));
+    if let Some(previous) = subject.get("act") {
+        act.insert("act".to_string(), previous.clone());
+    }
+    claims.extra.insert("act".to_string(), Value::Object(act));
+    confirm(&mut claims, jkt);
+
+    // Claims that needed encrypting still do
+    let access_token = match client.token_format {
+        TokenFormat::V4Public if encrypted => {
+            return Err(Error::oauth(
+                "invalid_request",
+                "An encrypted subject_token cannot be exchanged for a v4.public token",
+            ))
+        }
+        TokenFormat::Jwt => crate::token::issue(&claims, TokenFormat::Jwt, encrypted)?,
+        format => crate::token::issue(&claims, format, false)?,
+    };
+    token_response(claims, access_token, None, Some(issued_token_type)).await
+}
+
+/// `POST /oauth/token`
+pub async fn handle_token(req: Request<Body>) -> Result<Response<Body>> {
+    let config = crate::token::config()?;
+
+    let basic = basic_credentials(&req)?;
+    let jkt = crate::dpop::bind(&req).map_err(|e| match e {
+        Error::InvalidDpopProof(reason) => Error::oauth("invalid_dpop_proof", reason),
+        e => e,
+    })?;
+    let jkt = jkt.as_deref();
+    let form: TokenRequest = oauth_form(req).await?;
+
+    let grant_type = form
+        .grant_type
+        .as_deref()
+        .ok_or_else(|| Error::oauth("invalid_request", "Missing grant_type"))?;
+    let client = authenticate(basic, form.client_id.as_deref(), form.client_secret.as_deref())?;
+
+    match grant_type {
+        "client_credentials" => client_credentials(client, &form, config, jkt).await,
+        "refresh_token" => refresh_token(client, &form, config, jkt).await,
+        TOKEN_EXCHANGE => token_exchange(client, &form, config, jkt).await,
+        other => Err(Error::oauth(
+            "unsupported_grant_type",
+            format!("Grant type '{}' is not supported", other),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn granted(scope: &str) -> Map<String, Value> {
+        let mut claims = Map::new();
+        claims.insert("scope".to_string(), Value::String(scope.to_string()));
+        claims
+    }
+
+    fn refused(result: Result<Vec<String>>) -> bool {
+        matches!(result, Err(Error::OAuth { code: "invalid_scope", .. }))
+    }
+
+    #[test]
+    fn refresh_keeps_the_granted_scopes_when_none_are_requested() {
+        let scopes = refreshed_scopes(&None, &granted("orders:read orders:write")).unwrap();
+        assert_eq!(scopes, ["orders:read", "orders:write"]);
+    }
+
+    #[test]
+    fn refresh_narrows_to_the_requested_scopes() {
+        let scopes = refreshed_scopes(&Some("orders:read".to_string()), &granted("orders:read orders:write"));
+        assert_eq!(scopes.unwrap(), ["orders:read"]);
+    }
+
+    #[test]
+    fn refresh_refuses_an_empty_scope() {
+        for scope in ["", "   "] {
+            let scopes = refreshed_scopes(&Some(scope.to_string()), &granted("orders:read orders:write"));
+            assert!(refused(scopes), "{:?}", scope);
+        }
+    }
+
+    #[test]
+    fn refresh_refuses_wider_scopes() {
+        let scopes = refreshed_scopes(&Some("orders:admin".to_string()), &granted("orders:read"));
+        assert!(refused(scopes));
+    }
+}
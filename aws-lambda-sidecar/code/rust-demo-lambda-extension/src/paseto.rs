@@ -0,0 +1,551 @@
+//! PASETO v4 tokens, as an alternative to JWT
+//!
+//! PASETO fixes the algorithms per version and purpose, so a token cannot pick a weaker
+//! one the way a JWT `alg` header can. The sidecar issues and verifies both v4 purposes
+//! with the key ring the JWT path uses (see [`crate::keys`]):
+//!
+//! | Format      | Key                                          | Claims            |
+//! |-------------|----------------------------------------------|-------------------|
+//! | `v4.public` | the active `EdDSA` key signs (Ed25519)       | readable, signed  |
+//! | `v4.local`  | derived from the active HMAC secret (XChaCha20, BLAKE2b-MAC) | encrypted |
+//!
+//! Tokens carry the same claims as JWTs, except that `exp`, `nbf` and `iat` are RFC 3339
+//! strings as the spec requires. The footer names the key, `{"kid":"..."}`, when it has a
+//! `kid`. `/verify`, `/introspect`, `/revoke` and token exchange accept PASETO tokens
+//! wherever they accept JWTs, with the same checks.
+//!
+//! `MY_TOKEN_FORMAT` picks the format of `/my-token` tokens, and `token_format` in the
+//! client registry the format of each OAuth client's tokens.
+//!
+
+use crate::error::{Error, Result};
+use crate::keys::{PublicKey, SigningKey};
+use crate::token::{Claims, TokenFormat};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use blake2::digest::consts::{U32, U56};
+use blake2::digest::Mac;
+use blake2::Blake2bMac;
+use chacha20::cipher::{KeyIvInit, StreamCipher};
+use chacha20::XChaCha20;
+use jsonwebtoken::errors::ErrorKind;
+use ring::rand::{SecureRandom, SystemRandom};
+use ring::signature;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+const PUBLIC_HEADER: &str = "v4.public.";
+const LOCAL_HEADER: &str = "v4.local.";
+
+/// Ed25519 signature length
+const SIGNATURE_LEN: usize = 64;
+
+/// Lengths of the `v4.local` nonce and authentication tag
+const NONCE_LEN: usize = 32;
+const TAG_LEN: usize = 32;
+
+/// Claims PASETO writes as RFC 3339 strings instead of Unix seconds
+const TIME_CLAIMS: [&str; 3] = ["exp", "nbf", "iat"];
+
+/// Footer of the tokens the sidecar issues
+#[derive(Debug, Serialize, Deserialize)]
+struct Footer {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    kid: Option<String>,
+}
+
+/// A PASETO token whose signature or tag checked out
+pub struct Opened {
+    /// Version, purpose and key id, in place of a JOSE header
+    pub header: Value,
+    /// Claims with `exp`, `nbf` and `iat` converted back to Unix seconds
+    pub claims: Map<String, Value>,
+    pub encrypted: bool,
+}
+
+/// Whether `token` is a PASETO v4 token
+pub fn is_paseto(token: &str) -> bool {
+    token.starts_with(PUBLIC_HEADER) || token.starts_with(LOCAL_HEADER)
+}
+
+/// Whether `key` can issue and verify tokens in `format`
+fn usable(key: &SigningKey, format: TokenFormat) -> bool {
+    match format {
+        TokenFormat::V4Public => key.ed25519_key_pair().is_some(),
+        TokenFormat::V4Local => key.local_key().is_some(),
+        TokenFormat::Jwt => false,
+    }
+}
+
+/// Get the key that issues `format` tokens right now
+pub fn key(format: TokenFormat) -> Result<&'static SigningKey> {
+    crate::keys::signing_key_where(|key| usable(key, format)).map_err(|_| {
+        Error::Config(match format {
+            TokenFormat::V4Public => "v4.public tokens need an active EdDSA key in the key ring",
+            TokenFormat::V4Local => "v4.local tokens need an active HMAC key in the key ring",
+            TokenFormat::Jwt => "JWTs are not PASETO tokens",
+        }
+        .to_string())
+    })
+}
+
+/// Issue `claims` as a `v4.public` or `v4.local` token
+pub fn issue(claims: &Claims, format: TokenFormat) -> Result<String> {
+    let key = key(format)?;
+
+    let mut payload = match serde_json::to_value(claims)? {
+        Value::Object(payload) => payload,
+        _ => return Err(Error::Config("Claims must serialize to an object".to_string())),
+    };
+    for name in TIME_CLAIMS {
+        if let Some(seconds) = payload.get(name).and_then(Value::as_u64) {
+            payload.insert(name.to_string(), Value::String(to_rfc3339(seconds)?));
+        }
+    }
+    let payload = serde_json::to_vec(&payload)?;
+    let footer = match &key.kid {
+        Some(kid) => serde_json::to_vec(&Footer { kid: Some(kid.clone()) })?,
+        None => Vec::new(),
+    };
+
+    let (header, body) = match (format, key.ed25519_key_pair(), key.local_key()) {
+        (TokenFormat::V4Public, Some(key_pair), _) => (PUBLIC_HEADER, sign(key_pair, &payload, &footer)),
+        (TokenFormat::V4Local, _, Some(local_key)) => (LOCAL_HEADER, seal(local_key, &payload, &footer)?),
+        _ => return Err(Error::Config(format!("{:?} cannot be issued as PASETO", format))),
+    };
+    Ok(encode(header, &body, &footer))
+}
+
+/// Assemble a token from its header, body and (possibly empty) footer
+fn encode(header: &str, body: &[u8], footer: &[u8]) -> String {
+    let mut token = format!("{}{}", header, URL_SAFE_NO_PAD.encode(body));
+    if !footer.is_empty() {
+        token.push('.');
+        token.push_str(&URL_SAFE_NO_PAD.encode(footer));
+    }
+    token
+}
+
+/// Split a token into its format, header, decoded body and decoded footer
+fn split(token: &str) -> Result<(TokenFormat, &'static str, Vec<u8>, Vec<u8>)> {
+    let malformed = || Error::InvalidToken(ErrorKind::InvalidToken.into());
+    let b64 = |segment: &str| URL_SAFE_NO_PAD.decode(segment).map_err(|_| malformed());
+
+    let (format, header, rest) = if let Some(rest) = token.strip_prefix(PUBLIC_HEADER) {
+        (TokenFormat::V4Public, PUBLIC_HEADER, rest)
+    } else if let Some(rest) = token.strip_prefix(LOCAL_HEADER) {
+        (TokenFormat::V4Local, LOCAL_HEADER, rest)
+    } else {
+        return Err(malformed());
+    };
+    let (body, footer) = match rest.split_once('.') {
+        Some((body, footer)) => (b64(body)?, b64(footer)?),
+        None => (b64(rest)?, Vec::new()),
+    };
+    Ok((format, header, body, footer))
+}
+
+/// Check the signature or tag of a PASETO token and read its claims
+///
+/// Registered claims are left to the caller, as for JWTs.
+pub fn open(token: &str) -> Result<Opened> {
+    let malformed = || Error::InvalidToken(ErrorKind::InvalidToken.into());
+    let (format, _, body, footer) = split(token)?;
+
+    let kid = if footer.is_empty() {
+        None
+    } else {
+        serde_json::from_slice::<Footer>(&footer).map_err(|_| malformed())?.kid
+    };
+    let key = match &kid {
+        Some(kid) => crate::keys::key_ring()?
+            .verification_key(Some(kid), crate::token::now())
+            .filter(|key| usable(key, format))
+            .ok_or_else(|| Error::UnknownKey(format!("no active {} key with kid '{}'", purpose(format), kid)))?,
+        None => crate::keys::signing_key_where(|key| usable(key, format))
+            .map_err(|_| Error::UnknownKey(format!("no active {} key", purpose(format))))?,
+    };
+
+    let payload = match (format, &key.public, key.local_key()) {
+        (TokenFormat::V4Public, Some(PublicKey::Okp { x, .. }), _) => verify(x, &body, &footer)?,
+        (TokenFormat::V4Local, _, Some(local_key)) => unseal(local_key, &body, &footer)?,
+        _ => return Err(malformed()),
+    };
+
+    let mut claims: Map<String, Value> = serde_json::from_slice(&payload).map_err(|_| malformed())?;
+    for name in TIME_CLAIMS {
+        let Some(value) = claims.get(name) else {
+            continue;
+        };
+        let seconds = value.as_str().and_then(from_rfc3339).ok_or_else(malformed)?;
+        claims.insert(name.to_string(), Value::from(seconds));
+    }
+
+    Ok(Opened {
+        header: serde_json::json!({ "version": "v4", "purpose": purpose(format), "kid": kid }),
+        claims,
+        encrypted: format == TokenFormat::V4Local,
+    })
+}
+
+fn purpose(format: TokenFormat) -> &'static str {
+    match format {
+        TokenFormat::V4Local => "local",
+        _ => "public",
+    }
+}
+
+/// Pre-authentication encoding: the piece count, then each piece after its length (PASETO PAE)
+fn pae(pieces: &[&[u8]]) -> Vec<u8> {
+    // Lengths are little-endian 64-bit with the top bit cleared
+    let le64 = |n: usize| (n as u64 & (u64::MAX >> 1)).to_le_bytes();
+
+    let mut out = le64(pieces.len()).to_vec();
+    for piece in pieces {
+        out.extend(le64(piece.len()));
+        out.extend_from_slice(piece);
+    }
+    out
+}
+
+/// Sign a `v4.public` payload: payload || signature
+fn sign(key_pair: &signature::Ed25519KeyPair, payload: &[u8], footer: &[u8]) -> Vec<u8> {
+    let signature = key_pair.sign(&pae(&[PUBLIC_HEADER.as_bytes(), payload, footer, b""]));
+    [payload, signature.as_ref()].concat()
+}
+
+/// Check the signature of a `v4.public` body with an Ed25519 public key, returning the payload
+fn verify(public_key: &[u8], body: &[u8], footer: &[u8]) -> Result<Vec<u8>> {
+    if body.len() < SIGNATURE_LEN {
+        return Err(Error::InvalidToken(ErrorKind::InvalidToken.into()));
+    }
+    let (payload, signature) = body.split_at(body.len() - SIGNATURE_LEN);
+    signature::UnparsedPublicKey::new(&signature::ED25519, public_key)
+        .verify(&pae(&[PUBLIC_HEADER.as_bytes(), payload, footer, b""]), signature)
+        .map_err(|_| Error::InvalidToken(ErrorKind::InvalidSignature.into()))?;
+    Ok(payload.to_vec())
+}
+
+/// Split the `v4.local` key into the encryption key, nonce and authentication key for `nonce`
+fn split_key(key: &[u8; 32], nonce: &[u8]) -> Result<([u8; 32], [u8; 24], [u8; 32])> {
+    let invalid = |_| Error::Config("Invalid v4.local key".to_string());
+
+    let mut encryption = <Blake2bMac<U56> as Mac>::new_from_slice(key).map_err(invalid)?;
+    encryption.update(b"paseto-encryption-key");
+    encryption.update(nonce);
+    let encryption = encryption.finalize().into_bytes();
+
+    let mut authentication = <Blake2bMac<U32> as Mac>::new_from_slice(key).map_err(invalid)?;
+    authentication.update(b"paseto-auth-key-for-aead");
+    authentication.update(nonce);
+
+    let mut ek = [0u8; 32];
+    let mut n2 = [0u8; 24];
+    ek.copy_from_slice(&encryption[..32]);
+    n2.copy_from_slice(&encryption[32..]);
+    Ok((ek, n2, authentication.finalize().into_bytes().into()))
+}
+
+/// BLAKE2b-MAC of the pre-authentication encoding of a `v4.local` token
+fn local_tag(auth_key: &[u8; 32], nonce: &[u8], ciphertext: &[u8], footer: &[u8]) -> Result<Blake2bMac<U32>> {
+    let mut mac = <Blake2bMac<U32> as Mac>::new_from_slice(auth_key)
+        .map_err(|_| Error::Config("Invalid v4.local key".to_string()))?;
+    mac.update(&pae(&[LOCAL_HEADER.as_bytes(), nonce, ciphertext, footer, b""]));
+    Ok(mac)
+}
+
+/// Encrypt a `v4.local` payload under a fresh random nonce
+fn seal(key: &[u8; 32], payload: &[u8], footer: &[u8]) -> Result<Vec<u8>> {
+    let mut nonce = [0u8; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .map_err(|_| Error::Encryption("The system random generator failed".to_string()))?;
+    seal_with_nonce(key, &nonce, payload, footer)
+}
+
+/// Encrypt a `v4.local` payload: nonce || ciphertext || tag
+fn seal_with_nonce(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_LEN],
+    payload: &[u8],
+    footer: &[u8],
+) -> Result<Vec<u8>> {
+    let (ek, n2, ak) = split_key(key, nonce)?;
+
+    let mut ciphertext = payload.to_vec();
+    XChaCha20::new(&ek.into(), &n2.into()).apply_keystream(&mut ciphertext);
+    let tag = local_tag(&ak, nonce, &ciphertext, footer)?.finalize().into_bytes();
+
+    Ok([&nonce[..], &ciphertext, &tag].concat())
+}
+
+/// Check the tag of a `v4.local` body and decrypt it
+fn unseal(key: &[u8; 32], body: &[u8], footer: &[u8]) -> Result<Vec<u8>> {
+    if body.len() < NONCE_LEN + TAG_LEN {
+        return Err(Error::InvalidToken(ErrorKind::InvalidToken.into()));
+    }
+    let (nonce, rest) = body.split_at(NONCE_LEN);
+    let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let (ek, n2, ak) = split_key(key, nonce)?;
+
+    local_tag(&ak, nonce, ciphertext, footer)?
+        .verify_slice(tag)
+        .map_err(|_| Error::InvalidToken(ErrorKind::InvalidSignature.into()))?;
+
+    let mut payload = ciphertext.to_vec();
+    XChaCha20::new(&ek.into(), &n2.into()).apply_keystream(&mut payload);
+    Ok(payload)
+}
+
+fn to_rfc3339(seconds: u64) -> Result<String> {
+    i64::try_from(seconds)
+        .ok()
+        .and_then(|seconds| OffsetDateTime::from_unix_timestamp(seconds).ok())
+        .and_then(|at| at.format(&Rfc3339).ok())
+        .ok_or_else(|| Error::Config(format!("{} cannot be written as a date", seconds)))
+}
+
+fn from_rfc3339(value: &str) -> Option<u64> {
+    OffsetDateTime::parse(value, &Rfc3339)
+        .ok()
+        .and_then(|at| u64::try_from(at.unix_timestamp()).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Key of the `4-E-*` vectors
+    const LOCAL_KEY: &str = "707172737475767778797a7b7c7d7e7f808182838485868788898a8b8c8d8e8f";
+
+    /// Ed25519 key pair of the `4-S-*` vectors
+    const SECRET_KEY: &str = "b4cbfb43df4ce210727d953e4a713307fa19bb7d9f85041438d9e11b942a3774";
+    const PUBLIC_KEY: &str = "1eb9dbbbbc047c03fd70604e0071f0987e16b28b757225c11f00415d0e20b1a2";
+
+    struct LocalVector {
+        name: &'static str,
+        nonce: &'static str,
+        payload: &'static str,
+        footer: &'static str,
+        token: &'static str,
+    }
+
+    struct PublicVector {
+        name: &'static str,
+        payload: &'static str,
+        footer: &'static str,
+        token: &'static str,
+    }
+
+    /// `v4.local` vectors of the PASETO test suite without an implicit assertion, which the
+    /// sidecar never uses
+    const LOCAL_VECTORS: [LocalVector; 5] = [
+        LocalVector {
+            name: "4-E-1",
+            nonce: "0000000000000000000000000000000000000000000000000000000000000000",
+            payload: "{\"data\":\"this is a secret message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "",
+            token: concat!(
+                "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvSwsc",
+                "FlAl1pk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XJ5hOb_4v9RmDkneN0",
+                "S92dx0OW4pgy7omxgf3S8c3LlQg",
+            ),
+        },
+        LocalVector {
+            name: "4-E-2",
+            nonce: "0000000000000000000000000000000000000000000000000000000000000000",
+            payload: "{\"data\":\"this is a hidden message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "",
+            token: concat!(
+                "v4.local.AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAQAr68PS4AXe7If_ZgesdkUMvS2cs",
+                "Cgglvpk5HC0e8kApeaqMfGo_7OpBnwJOAbY9V7WU6abu74MmcUE8YWAiaArVI8XIemu9chy3WVKvRBfg",
+                "6t8wwYHK0ArLxxfZP73W_vfwt5A",
+            ),
+        },
+        LocalVector {
+            name: "4-E-3",
+            nonce: "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            payload: "{\"data\":\"this is a secret message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "",
+            token: concat!(
+                "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WkwM",
+                "sYXw6FSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t6-tyebyWG6Ov7kKvB",
+                "dkrrAJ837lKP3iDag2hzUPHuMKA",
+            ),
+        },
+        LocalVector {
+            name: "4-E-4",
+            nonce: "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            payload: "{\"data\":\"this is a hidden message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "",
+            token: concat!(
+                "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WiA8",
+                "rd3wgFSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t4gt6TiLm55vIH8c_l",
+                "GxxZpE3AWlH4WTR0v45nsWoU3gQ",
+            ),
+        },
+        LocalVector {
+            name: "4-E-5",
+            nonce: "df654812bac492663825520ba2f6e67cf5ca5bdc13d4e7507a98cc4c2fcc3ad8",
+            payload: "{\"data\":\"this is a secret message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "{\"kid\":\"zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN\"}",
+            token: concat!(
+                "v4.local.32VIErrEkmY4JVILovbmfPXKW9wT1OdQepjMTC_MOtjA4kiqw7_tcaOM5GNEcnTxl60WkwM",
+                "sYXw6FSNb_UdJPXjpzm0KW9ojM5f4O2mRvE2IcweP-PRdoHjd5-RHCiExR1IK6t4x-RMNXtQNbz7FvFZ",
+                "_G-lFpk5RG3EOrwDL6CgDqcerSQ.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2NO",
+                "eTlEZmdMMVc2MGhhTiJ9",
+            ),
+        },
+    ];
+
+    /// `v4.public` vectors of the PASETO test suite without an implicit assertion
+    const PUBLIC_VECTORS: [PublicVector; 2] = [
+        PublicVector {
+            name: "4-S-1",
+            payload: "{\"data\":\"this is a signed message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "",
+            token: concat!(
+                "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMV",
+                "QwMDowMDowMCswMDowMCJ9bg_XBBzds8lTZShVlwwKSgeKpLT3yukTw6JUz3W4h_ExsQV-P0V54zemZD",
+                "cAxFaSeef1QlXEFtkqxT1ciiQEDA",
+            ),
+        },
+        PublicVector {
+            name: "4-S-2",
+            payload: "{\"data\":\"this is a signed message\",\"exp\":\"2022-01-01T00:00:00+00:00\"}",
+            footer: "{\"kid\":\"zVhMiPBP9fRf2snEcT7gFTioeA9COcNy9DfgL1W60haN\"}",
+            token: concat!(
+                "v4.public.eyJkYXRhIjoidGhpcyBpcyBhIHNpZ25lZCBtZXNzYWdlIiwiZXhwIjoiMjAyMi0wMS0wMV",
+                "QwMDowMDowMCswMDowMCJ9v3Jt8mx_TdM2ceTGoqwrh4yDFn0XsHvvV_D0DtwQxVrJEBMl0F2caAdgnp",
+                "Klt4p7xBnx1HcO-SPo8FPp214HDw.eyJraWQiOiJ6VmhNaVBCUDlmUmYyc25FY1Q3Z0ZUaW9lQTlDT2N",
+                "OeTlEZmdMMVc2MGhhTiJ9",
+            ),
+        },
+    ];
+
+    fn hex(value: &str) -> Vec<u8> {
+        (0..value.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn local_key() -> [u8; 32] {
+        hex(LOCAL_KEY).try_into().unwrap()
+    }
+
+    fn key_pair() -> signature::Ed25519KeyPair {
+        signature::Ed25519KeyPair::from_seed_and_public_key(&hex(SECRET_KEY), &hex(PUBLIC_KEY)).unwrap()
+    }
+
+    fn assert_invalid<T: std::fmt::Debug>(result: Result<T>) {
+        assert!(matches!(result, Err(Error::InvalidToken(_))), "expected a rejection, got {:?}", result);
+    }
+
+    #[test]
+    fn local_vectors_encrypt() {
+        for vector in &LOCAL_VECTORS {
+            let nonce: [u8; NONCE_LEN] = hex(vector.nonce).try_into().unwrap();
+            let footer = vector.footer.as_bytes();
+            let body = seal_with_nonce(&local_key(), &nonce, vector.payload.as_bytes(), footer).unwrap();
+            let token = encode(LOCAL_HEADER, &body, footer);
+            assert_eq!(token, vector.token, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn local_vectors_decrypt() {
+        for vector in &LOCAL_VECTORS {
+            let (format, _, body, footer) = split(vector.token).unwrap();
+            assert_eq!(format, TokenFormat::V4Local, "{}", vector.name);
+            assert_eq!(footer, vector.footer.as_bytes(), "{}", vector.name);
+            let payload = unseal(&local_key(), &body, &footer).unwrap();
+            assert_eq!(payload, vector.payload.as_bytes(), "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn public_vectors_sign() {
+        for vector in &PUBLIC_VECTORS {
+            let body = sign(&key_pair(), vector.payload.as_bytes(), vector.footer.as_bytes());
+            let token = encode(PUBLIC_HEADER, &body, vector.footer.as_bytes());
+            assert_eq!(token, vector.token, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn public_vectors_verify() {
+        for vector in &PUBLIC_VECTORS {
+            let (format, _, body, footer) = split(vector.token).unwrap();
+            assert_eq!(format, TokenFormat::V4Public, "{}", vector.name);
+            let payload = verify(&hex(PUBLIC_KEY), &body, &footer).unwrap();
+            assert_eq!(payload, vector.payload.as_bytes(), "{}", vector.name);
+        }
+    }
+
+    /// As `4-F-*`: tokens of another version, or of the other purpose, are not mistaken for
+    /// the one expected
+    #[test]
+    fn wrong_header_is_rejected() {
+        let local = LOCAL_VECTORS[2].token;
+        for token in [
+            local.replacen("v4.", "v3.", 1),
+            local.replacen("v4.local.", "v4.secret.", 1),
+            local.replacen("v4.local.", "V4.LOCAL.", 1),
+            local.replacen("v4.local.", "v4.local", 1),
+        ] {
+            assert_invalid(split(&token));
+        }
+
+        // A v4.local body checked as v4.public, and the other way round
+        let (_, _, body, footer) = split(local).unwrap();
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body, &footer));
+        let (_, _, body, footer) = split(PUBLIC_VECTORS[0].token).unwrap();
+        assert_invalid(unseal(&local_key(), &body, &footer));
+    }
+
+    #[test]
+    fn truncated_body_is_rejected() {
+        let (_, _, body, footer) = split(LOCAL_VECTORS[2].token).unwrap();
+        assert_invalid(unseal(&local_key(), &body[..NONCE_LEN + TAG_LEN - 1], &footer));
+        assert_invalid(unseal(&local_key(), &body[..body.len() - 1], &footer));
+
+        let (_, _, body, footer) = split(PUBLIC_VECTORS[0].token).unwrap();
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body[..SIGNATURE_LEN - 1], &footer));
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body[1..], &footer));
+    }
+
+    #[test]
+    fn footer_mismatch_is_rejected() {
+        let (_, _, body, _) = split(LOCAL_VECTORS[4].token).unwrap();
+        assert_invalid(unseal(&local_key(), &body, b""));
+        assert_invalid(unseal(&local_key(), &body, br#"{"kid":"another"}"#));
+
+        let (_, _, body, _) = split(PUBLIC_VECTORS[1].token).unwrap();
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body, b""));
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body, br#"{"kid":"another"}"#));
+    }
+
+    #[test]
+    fn bad_tag_is_rejected() {
+        let (_, _, mut body, footer) = split(LOCAL_VECTORS[2].token).unwrap();
+        let last = body.len() - 1;
+        body[last] ^= 1;
+        assert_invalid(unseal(&local_key(), &body, &footer));
+
+        // Ciphertext and nonce are covered by the tag too
+        let (_, _, mut body, footer) = split(LOCAL_VECTORS[2].token).unwrap();
+        body[NONCE_LEN] ^= 1;
+        assert_invalid(unseal(&local_key(), &body, &footer));
+        body[NONCE_LEN] ^= 1;
+        body[0] ^= 1;
+        assert_invalid(unseal(&local_key(), &body, &footer));
+
+        let (_, _, mut body, footer) = split(PUBLIC_VECTORS[0].token).unwrap();
+        let last = body.len() - 1;
+        body[last] ^= 1;
+        assert_invalid(verify(&hex(PUBLIC_KEY), &body, &footer));
+    }
+}
@@ -0,0 +1,184 @@
+//! Lambda Runtime API Proxy (LRAP)
+//!
+//! The function runtime is pointed at this proxy by the `lrap-wrapper` script instead of
+//! the real Runtime API. Every call is forwarded to [`crate::env::sandbox_runtime_api`],
+//! and an [`Interceptor`] gets a chance to inspect or rewrite events on their way to the
+//! handler and responses on their way back to Lambda.
+//!
+
+use crate::error::{Error, Result};
+use crate::extension::send_request;
+use hyper::body::Bytes;
+use hyper::header::{HeaderMap, CONTENT_LENGTH, HOST, TRANSFER_ENCODING};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server};
+use log::{debug, info, warn};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Hook into the invocations flowing through the proxy
+///
+/// Both methods default to forwarding the payload untouched.
+pub trait Interceptor: Send + Sync + 'static {
+    /// Inspect or rewrite an event before the function handler sees it
+    ///
+    /// Returning an error rejects the invocation: the proxy reports it to Lambda as
+    /// an invocation error and hands the runtime the next event instead.
+    fn on_event(&self, request_id: &str, headers: &HeaderMap, event: Bytes) -> Result<Bytes> {
+        let _ = (request_id, headers);
+        Ok(event)
+    }
+
+    /// Inspect or rewrite the handler's response before Lambda receives it
+    fn on_response(&self, request_id: &str, response: Bytes) -> Result<Bytes> {
+        let _ = request_id;
+        Ok(response)
+    }
+}
+
+/// Interceptor forwarding every event and response unchanged
+pub struct Passthrough;
+
+impl Interceptor for Passthrough {}
+
+/// Bind the proxy listener
+///
+/// Binding happens eagerly so the listener is ready before the extension registers
+/// and Lambda starts the runtime. The returned future serves until it fails.
+///
+pub fn bind(interceptor: Arc<dyn Interceptor>) -> Result<impl Future<Output = Result<()>>> {
+    let addr = SocketAddr::from(([127, 0, 0, 1], crate::env::lrap_listener_port()?));
+    let builder = Server::try_bind(&addr).map_err(Error::Server)?;
+
+    let make_svc = make_service_fn(move |_conn| {
+        let interceptor = interceptor.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let interceptor = interceptor.clone();
+                async move {
+                    Ok::<_, Infallible>(
+                        handle(interceptor.as_ref(), req)
+                            .await
+                            .unwrap_or_else(Error::into_response),
+                    )
+                }
+            }))
+        }
+    });
+
+    info!("[LRAP:Proxy] Runtime API proxy running on http://{}", addr);
+
+    Ok(async move { builder.serve(make_svc).await.map_err(Error::Server) })
+}
+
+/// Route a runtime call to the matching forwarder
+async fn handle(interceptor: &dyn Interceptor, req: Request<Body>) -> Result<Response<Body>> {
+    let prefix = format!("/{}/runtime/invocation/", crate::LAMBDA_RUNTIME_API_VERSION);
+    let invocation = req.uri().path().strip_prefix(&prefix).map(str::to_owned);
+
+    match (req.method(), invocation.as_deref()) {
+        (&Method::GET, Some("next")) => next_invocation(interceptor, req).await,
+        (&Method::POST, Some(path)) if path.ends_with("/response") => {
+            let request_id = path.trim_end_matches("/response").to_owned();
+            let (mut parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await?;
+            let body = interceptor.on_response(&request_id, body)?;
+            parts.headers.remove(CONTENT_LENGTH);
+            parts.headers.remove(TRANSFER_ENCODING);
+            forward(Request::from_parts(parts, Body::from(body))).await
+        }
+        // `/invocation/{id}/error`, `/init/error` and anything else pass through as-is
+        _ => forward(req).await,
+    }
+}
+
+/// Fetch the next event for the runtime, giving the interceptor a say on each one
+async fn next_invocation(interceptor: &dyn Interceptor, req: Request<Body>) -> Result<Response<Body>> {
+    let (parts, _) = req.into_parts();
+
+    loop {
+        let mut request = Request::new(Body::empty());
+        *request.uri_mut() = parts.uri.clone();
+        *request.headers_mut() = parts.headers.clone();
+
+        let response = forward(request).await?;
+        if !response.status().is_success() {
+            return Ok(response);
+        }
+
+        let (mut parts, body) = response.into_parts();
+        let event = hyper::body::to_bytes(body).await?;
+        let request_id = parts
+            .headers
+            .get("Lambda-Runtime-Aws-Request-Id")
+            .and_then(|v| v.to_str().ok())
+            .ok_or_else(|| {
+                Error::Protocol("Runtime API next invocation missing 'Lambda-Runtime-Aws-Request-Id' header".to_string())
+            })?
+            .to_owned();
+
+        match interceptor.on_event(&request_id, &parts.headers, event) {
+            Ok(event) => {
+                debug!("[LRAP:Proxy] Handing invocation {} to the runtime", request_id);
+                parts.headers.remove(CONTENT_LENGTH);
+                parts.headers.remove(TRANSFER_ENCODING);
+                return Ok(Response::from_parts(parts, Body::from(event)));
+            }
+            Err(e) => {
+                warn!("[LRAP:Proxy] Rejected invocation {}: {}", request_id, e);
+                reject_invocation(&request_id, &e).await?;
+            }
+        }
+    }
+}
+
+/// Report a rejected invocation to Lambda on behalf of the function
+async fn reject_invocation(request_id: &str, error: &Error) -> Result<()> {
+    let body = serde_json::json!({
+        "errorMessage": error.to_string(),
+        "errorType": error.error_type(),
+    });
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "/{}/runtime/invocation/{}/error",
+            crate::LAMBDA_RUNTIME_API_VERSION,
+            request_id
+        ))
+        .header("Lambda-Runtime-Function-Error-Type", error.error_type())
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))?;
+
+    let response = forward(request).await?;
+    if !response.status().is_success() {
+        return Err(Error::Protocol(format!(
+            "Runtime API rejected invocation error for {}: {}",
+            request_id,
+            response.status()
+        )));
+    }
+    Ok(())
+}
+
+/// Send the runtime call to the real Runtime API and hand back its response
+async fn forward(req: Request<Body>) -> Result<Response<Body>> {
+    let (mut parts, body) = req.into_parts();
+
+    let path_and_query = parts
+        .uri
+        .path_and_query()
+        .map(|p| p.as_str().to_owned())
+        .unwrap_or_else(|| "/".to_string());
+
+    parts.uri = hyper::Uri::builder()
+        .scheme("http")
+        .authority(crate::env::sandbox_runtime_api()?)
+        .path_and_query(path_and_query)
+        .build()?;
+    parts.headers.remove(HOST);
+
+    send_request(Request::from_parts(parts, body)).await
+}
@@ -0,0 +1,342 @@
+//! Refresh tokens with rotation and reuse detection (RFC 6749 §6)
+//!
+//! Refresh tokens are opaque random strings. What each one stands for lives in a
+//! [`RefreshStore`], keyed by the SHA-256 of the token so the store never holds a usable
+//! token. Redeeming a token rotates it: the old one is marked used and a successor is
+//! issued in the same family. Presenting a used token again means it was copied, so the
+//! whole family is revoked (OAuth 2.0 Security BCP §4.14.2).
+//!
+//! | Variable                    | Default                     |
+//! |-----------------------------|-----------------------------|
+//! | `REFRESH_TOKEN_TTL_SECONDS` | `86400`, `0` disables refresh tokens |
+//!
+//! The default [`MemoryStore`] lives as long as the execution environment, so refresh
+//! tokens do not survive a cold start; install another store to share them.
+//!
+
+use crate::env::parsed_var;
+use crate::error::{Error, Result};
+use crate::token::TokenFormat;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::warn;
+use once_cell::sync::OnceCell;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+/// Random bytes in a refresh token
+const TOKEN_BYTES: usize = 32;
+
+static TTL: OnceCell<u64> = OnceCell::new();
+static STORE: OnceCell<Arc<dyn RefreshStore>> = OnceCell::new();
+
+/// What a refresh token stands for
+#[derive(Debug, Clone)]
+pub struct Grant {
+    /// Shared by every token rotated from the same original grant
+    pub family: String,
+    pub subject: String,
+    /// Client the token was issued to, `None` for `/my-token`
+    pub client_id: Option<String>,
+    pub audience: Vec<String>,
+    /// Custom claims copied into every access token, including `scope`
+    pub claims: Map<String, Value>,
+    /// How access tokens from this grant are issued
+    pub format: TokenFormat,
+    /// Whether access tokens from this grant are wrapped in a JWE
+    pub encrypted: bool,
+    /// DPoP key thumbprint every redemption must prove possession of
+    pub jkt: Option<String>,
+    pub issued_at: u64,
+    pub expires_at: u64,
+}
+
+impl Grant {
+    /// Start a new family
+    pub fn new(
+        subject: String,
+        client_id: Option<String>,
+        audience: Vec<String>,
+        claims: Map<String, Value>,
+    ) -> Self {
+        Grant {
+            family: uuid::Uuid::new_v4().to_string(),
+            subject,
+            client_id,
+            audience,
+            claims,
+            format: TokenFormat::Jwt,
+            encrypted: false,
+            jkt: None,
+            issued_at: 0,
+            expires_at: 0,
+        }
+    }
+}
+
+/// What the store knew about a presented token
+#[derive(Debug)]
+pub enum Redeemed {
+    /// First use: the token is now marked used
+    Fresh(Grant),
+    /// The token was already used once
+    Reused(Grant),
+    /// Issued to another client; the token was left untouched
+    WrongClient,
+    /// Never issued, expired, or its family was revoked
+    Unknown,
+}
+
+/// Future returned by [`RefreshStore`] methods
+pub type StoreFuture<'a, T> = Pin<Box<dyn Future<Output = Result<T>> + Send + 'a>>;
+
+/// Where refresh token state is kept
+pub trait RefreshStore: Send + Sync + 'static {
+    /// Remember a newly issued token
+    fn insert<'a>(&'a self, token_hash: String, grant: Grant) -> StoreFuture<'a, ()>;
+
+    /// Mark a token issued to `client_id` used and report its previous state, atomically
+    fn redeem<'a>(&'a self, token_hash: &'a str, client_id: Option<&'a str>) -> StoreFuture<'a, Redeemed>;
+
+    /// The grant behind a token that can still be redeemed, without using it up
+    fn lookup<'a>(&'a self, token_hash: &'a str) -> StoreFuture<'a, Option<Grant>>;
+
+    /// Forget every token of a family, including ones inserted later
+    fn revoke_family<'a>(&'a self, family: &'a str) -> StoreFuture<'a, ()>;
+}
+
+/// Store keeping refresh tokens in process memory
+#[derive(Default)]
+pub struct MemoryStore {
+    state: Mutex<MemoryState>,
+}
+
+#[derive(Default)]
+struct MemoryState {
+    /// Token hash to grant, and whether it was used
+    tokens: HashMap<String, (Grant, bool)>,
+    /// Revoked family to the time its last token expires
+    revoked: HashMap<String, u64>,
+}
+
+impl MemoryState {
+    /// Drop expired tokens and revocations nothing can match any more
+    fn purge(&mut self, now: u64) {
+        self.tokens.retain(|_, (grant, _)| grant.expires_at > now);
+        self.revoked.retain(|_, until| *until > now);
+    }
+}
+
+impl MemoryStore {
+    fn state(&self) -> std::sync::MutexGuard<'_, MemoryState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl RefreshStore for MemoryStore {
+    fn insert<'a>(&'a self, token_hash: String, grant: Grant) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let mut state = self.state();
+            state.purge(crate::token::now());
+            state.tokens.insert(token_hash, (grant, false));
+            Ok(())
+        })
+    }
+
+    fn redeem<'a>(&'a self, token_hash: &'a str, client_id: Option<&'a str>) -> StoreFuture<'a, Redeemed> {
+        Box::pin(async move {
+            let mut guard = self.state();
+            let state = &mut *guard;
+            state.purge(crate::token::now());
+
+            let revoked = &state.revoked;
+            let Some((grant, used)) = state
+                .tokens
+                .get_mut(token_hash)
+                .filter(|(grant, _)| !revoked.contains_key(&grant.family))
+            else {
+                return Ok(Redeemed::Unknown);
+            };
+
+            if grant.client_id.as_deref() != client_id {
+                return Ok(Redeemed::WrongClient);
+            }
+            if std::mem::replace(used, true) {
+                Ok(Redeemed::Reused(grant.clone()))
+            } else {
+                Ok(Redeemed::Fresh(grant.clone()))
+            }
+        })
+    }
+
+    fn lookup<'a>(&'a self, token_hash: &'a str) -> StoreFuture<'a, Option<Grant>> {
+        Box::pin(async move {
+            let mut state = self.state();
+            state.purge(crate::token::now());
+            Ok(state
+                .tokens
+                .get(token_hash)
+                .filter(|(grant, used)| !used && !state.revoked.contains_key(&grant.family))
+                .map(|(grant, _)| grant.clone()))
+        })
+    }
+
+    fn revoke_family<'a>(&'a self, family: &'a str) -> StoreFuture<'a, ()> {
+        Box::pin(async move {
+            let mut state = self.state();
+            let mut until = crate::token::now() + ttl()?;
+            state.tokens.retain(|_, (grant, _)| {
+                if grant.family != family {
+                    return true;
+                }
+                until = until.max(grant.expires_at);
+                false
+            });
+            state.revoked.insert(family.to_string(), until);
+            Ok(())
+        })
+    }
+}
+
+/// Seconds a refresh token stays valid, `0` when refresh tokens are disabled
+pub fn ttl() -> Result<u64> {
+    TTL.get_or_try_init(|| parsed_var("REFRESH_TOKEN_TTL_SECONDS", 86_400))
+        .copied()
+}
+
+/// Use `store` for refresh tokens; must be called before the first token is issued
+pub fn install(store: Arc<dyn RefreshStore>) -> Result<()> {
+    STORE
+        .set(store)
+        .map_err(|_| Error::Config("A refresh token store is already installed".to_string()))
+}
+
+fn store() -> Result<&'static Arc<dyn RefreshStore>> {
+    STORE
+        .get()
+        .ok_or_else(|| Error::Config("No refresh token store is installed".to_string()))
+}
+
+/// Hash a token for use as a store key
+fn token_hash(token: &str) -> String {
+    URL_SAFE_NO_PAD.encode(ring::digest::digest(&ring::digest::SHA256, token.as_bytes()))
+}
+
+/// Issue a refresh token for `grant`, or `None` when refresh tokens are disabled
+pub async fn issue(mut grant: Grant) -> Result<Option<String>> {
+    let ttl = ttl()?;
+    if ttl == 0 {
+        return Ok(None);
+    }
+
+    let mut bytes = [0u8; TOKEN_BYTES];
+    SystemRandom::new()
+        .fill(&mut bytes)
+        .map_err(|_| Error::Config("The system random generator failed".to_string()))?;
+    let token = URL_SAFE_NO_PAD.encode(bytes);
+
+    grant.issued_at = crate::token::now();
+    grant.expires_at = grant.issued_at + ttl;
+    store()?.insert(token_hash(&token), grant).await?;
+    Ok(Some(token))
+}
+
+/// Redeem a refresh token presented by `client_id`, revoking its family when it was already used
+///
+/// The caller issues the successor with [`issue`], keeping the returned family.
+pub async fn redeem(token: &str, client_id: Option<&str>) -> Result<Grant> {
+    let store = store()?;
+    match store.redeem(&token_hash(token), client_id).await? {
+        Redeemed::Fresh(grant) => Ok(grant),
+        Redeemed::Reused(grant) => {
+            warn!(
+                "Refresh token reused for {}, revoking token family {}",
+                grant.subject, grant.family
+            );
+            store.revoke_family(&grant.family).await?;
+            Err(Error::oauth(
+                "invalid_grant",
+                "Refresh token was already used; every token issued from it is revoked",
+            ))
+        }
+        Redeemed::WrongClient => Err(Error::oauth(
+            "invalid_grant",
+            "Refresh token was issued to another client",
+        )),
+        Redeemed::Unknown => Err(Error::oauth("invalid_grant", "Unknown, expired or revoked refresh token")),
+    }
+}
+
+/// The grant behind a refresh token that can still be redeemed
+pub async fn lookup(token: &str) -> Result<Option<Grant>> {
+    store()?.lookup(&token_hash(token)).await
+}
+
+/// Revoke every refresh token issued from the same original grant
+pub async fn revoke(grant: &Grant) -> Result<()> {
+    store()?.revoke_family(&grant.family).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn grant(client_id: Option<&str>) -> Grant {
+        // Tests share the process-wide store; any one of them may install it
+        let _ = install(Arc::new(MemoryStore::default()));
+        Grant::new("alice".to_string(), client_id.map(str::to_owned), Vec::new(), Map::new())
+    }
+
+    async fn issued(grant: Grant) -> String {
+        issue(grant).await.unwrap().unwrap()
+    }
+
+    fn refused(result: Result<Grant>) -> bool {
+        matches!(result, Err(Error::OAuth { code: "invalid_grant", .. }))
+    }
+
+    #[tokio::test]
+    async fn reuse_revokes_the_family() {
+        let first = issued(grant(None)).await;
+        let successor = issued(redeem(&first, None).await.unwrap()).await;
+
+        assert!(refused(redeem(&first, None).await));
+        assert!(lookup(&successor).await.unwrap().is_none());
+        assert!(refused(redeem(&successor, None).await));
+    }
+
+    #[tokio::test]
+    async fn successors_of_a_revoked_family_are_refused() {
+        let first = issued(grant(None)).await;
+        let redeemed = redeem(&first, None).await.unwrap();
+        revoke(&redeemed).await.unwrap();
+
+        let successor = issued(redeemed).await;
+        assert!(lookup(&successor).await.unwrap().is_none());
+        assert!(refused(redeem(&successor, None).await));
+    }
+
+    #[tokio::test]
+    async fn other_clients_are_refused_without_using_the_token() {
+        let token = issued(grant(Some("orders"))).await;
+
+        assert!(refused(redeem(&token, Some("billing")).await));
+        assert!(refused(redeem(&token, None).await));
+        assert!(redeem(&token, Some("orders")).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn expired_tokens_are_refused() {
+        let mut expired = grant(None);
+        expired.issued_at = crate::token::now() - 20;
+        expired.expires_at = crate::token::now() - 10;
+        store().unwrap().insert(token_hash("expired"), expired).await.unwrap();
+
+        assert!(lookup("expired").await.unwrap().is_none());
+        assert!(refused(redeem("expired", None).await));
+    }
+}
@@ -0,0 +1,185 @@
+//! Token revocation (RFC 7009)
+//!
+//! `POST /revoke` kills a token before it expires. Access tokens are denied by `jti`
+//! until their `exp`, and `/verify` refuses them with `token_revoked`. Revoking a refresh
+//! token revokes its whole family (see [`crate::refresh`]).
+//!
+//! Tokens issued to an OAuth client can only be revoked by that client, authenticated as
+//! on `/oauth/token`. As the RFC requires, unknown, invalid and expired tokens are
+//! answered with `200` too, so callers cannot probe which tokens exist.
+//!
+//! A denylist can be shipped in the layer so every environment starts with it:
+//!
+//! | Variable             | Meaning                                                   |
+//! |----------------------|-----------------------------------------------------------|
+//! | `JWT_DENYLIST`       | denylist as inline JSON                                   |
+//! | `JWT_DENYLIST_PATH`  | denylist as a JSON file, e.g. `/opt/keys/denylist.json`   |
+//!
+//! ```json
+//! [
+//!   "0f8fad5b-d9cb-469f-a165-70867728950e",
+//!   { "jti": "7c9e6679-7425-40de-944b-e07fc1f90ae7", "exp": 1790000000 }
+//! ]
+//! ```
+//!
+//! A bare `jti` stays denied for the life of the process; one with `exp` until then.
+//!
+
+use crate::error::{Error, Result};
+use crate::oauth::{authenticate, basic_credentials, oauth_form, Client};
+use hyper::{Body, Request, Response, StatusCode};
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+static DENYLIST: OnceCell<Denylist> = OnceCell::new();
+
+/// One denylist entry shipped with the layer
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum DenylistEntry {
+    Jti(String),
+    Expiring { jti: String, exp: u64 },
+}
+
+/// Revoked `jti`s and when they stop mattering (`None` for never)
+#[derive(Debug, Default)]
+pub struct Denylist {
+    entries: Mutex<HashMap<String, Option<u64>>>,
+}
+
+impl Denylist {
+    /// Load the shipped denylist from `JWT_DENYLIST` / `JWT_DENYLIST_PATH`, empty when neither is set
+    fn from_env() -> Result<Self> {
+        let denylist = Denylist::default();
+        let shipped = crate::env::json_var::<Vec<DenylistEntry>>("JWT_DENYLIST", "JWT_DENYLIST_PATH")?;
+        let Some((entries, source)) = shipped else {
+            return Ok(denylist);
+        };
+
+        let count = entries.len();
+        for entry in entries {
+            match entry {
+                DenylistEntry::Jti(jti) => denylist.revoke(jti, None),
+                DenylistEntry::Expiring { jti, exp } => denylist.revoke(jti, Some(exp)),
+            }
+        }
+        info!("Loaded {} revoked token ids from {}", count, source);
+        Ok(denylist)
+    }
+
+    fn entries(&self) -> std::sync::MutexGuard<'_, HashMap<String, Option<u64>>> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Deny `jti` until `until`, or for good
+    pub fn revoke(&self, jti: String, until: Option<u64>) {
+        let now = crate::token::now();
+        if until.is_some_and(|until| until <= now) {
+            return;
+        }
+
+        let mut entries = self.entries();
+        entries.retain(|_, until| until.is_none_or(|until| until > now));
+        entries.insert(jti, until);
+    }
+
+    /// Whether `jti` is currently denied
+    pub fn is_revoked(&self, jti: &str) -> bool {
+        self.entries()
+            .get(jti)
+            .is_some_and(|until| until.is_none_or(|until| until > crate::token::now()))
+    }
+}
+
+/// Get the denylist, loading the shipped entries on first use
+pub fn denylist() -> Result<&'static Denylist> {
+    DENYLIST.get_or_try_init(Denylist::from_env)
+}
+
+/// Form parameters of a revocation request (§2.1)
+#[derive(Debug, Default, Deserialize)]
+struct RevocationRequest {
+    token: Option<String>,
+    token_type_hint: Option<String>,
+    client_id: Option<String>,
+    client_secret: Option<String>,
+}
+
+/// Refuse to act on a token issued to a client other than the caller
+fn check_owner(owner: Option<&str>, client: Option<&Client>) -> Result<()> {
+    match (owner, client) {
+        (None, _) => Ok(()),
+        (Some(owner), Some(client)) if client.id == owner => Ok(()),
+        (Some(_), Some(_)) => Err(Error::oauth(
+            "unauthorized_client",
+            "Token was issued to another client",
+        )),
+        (Some(_), None) => Err(Error::oauth("invalid_client", "Client authentication is required")),
+    }
+}
+
+/// Deny a self-issued access token until it expires; anything else is ignored
+fn revoke_access_token(token: &str, client: Option<&Client>) -> Result<()> {
+    let claims = match crate::verify::validate(token) {
+        Ok(verified) => verified.claims,
+        Err(e) if e.is_token_rejection() => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    check_owner(claims.get("client_id").and_then(Value::as_str), client)?;
+
+    let Some(jti) = claims.get("jti").and_then(Value::as_str) else {
+        return Ok(());
+    };
+    // Keep the entry as long as `/verify` would still accept the token
+    let leeway = crate::verify::config()?.leeway;
+    let until = claims.get("exp").and_then(Value::as_u64).map(|exp| exp.saturating_add(leeway));
+
+    info!("Revoking token {}", jti);
+    denylist()?.revoke(jti.to_owned(), until);
+    Ok(())
+}
+
+/// Revoke the family of a refresh token; unknown tokens are ignored
+async fn revoke_refresh_token(token: &str, client: Option<&Client>) -> Result<()> {
+    let Some(grant) = crate::refresh::lookup(token).await? else {
+        return Ok(());
+    };
+    check_owner(grant.client_id.as_deref(), client)?;
+
+    info!("Revoking refresh token family {}", grant.family);
+    crate::refresh::revoke(&grant).await
+}
+
+/// `POST /revoke`
+pub async fn handle_revoke(req: Request<Body>) -> Result<Response<Body>> {
+    let basic = basic_credentials(&req)?;
+    let form: RevocationRequest = oauth_form(req).await?;
+    let client = authenticate(basic, form.client_id.as_deref(), form.client_secret.as_deref())?;
+
+    let token = form
+        .token
+        .as_deref()
+        .ok_or_else(|| Error::oauth("invalid_request", "Missing token"))?;
+
+    // The hint only speeds up the lookup (§2.1); the token's shape tells us its type
+    match form.token_type_hint.as_deref() {
+        None | Some("access_token") | Some("refresh_token") => {}
+        Some(hint) => {
+            return Err(Error::oauth(
+                "unsupported_token_type",
+                format!("Cannot revoke tokens of type '{}'", hint),
+            ))
+        }
+    }
+    if crate::verify::is_access_token(token) {
+        revoke_access_token(token, client)?;
+    } else {
+        revoke_refresh_token(token, client).await?;
+    }
+
+    Ok(Response::builder().status(StatusCode::OK).body(Body::empty())?)
+}
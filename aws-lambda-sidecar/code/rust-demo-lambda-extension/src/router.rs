@@ -0,0 +1,294 @@
+//! Request routing for the local HTTP server
+//!
+//! A [`Router`] maps path patterns to handlers, one per set of methods. Segments written
+//! `{name}` match any single segment, which handlers read with [`param`]. The router also
+//! answers what no handler should have to:
+//!
+//! - `HEAD` on routes that list it next to `GET`, with the headers `GET` would send;
+//! - `OPTIONS` with `204` and the `Allow` header;
+//! - `405` with `Allow` when the path exists but not for the method;
+//! - `404` listing every route.
+//!
+//! `HEAD` runs the `GET` handler, so only routes whose `GET` is safe to repeat should
+//! list it: `HEAD /my-token` would mint a token no one reads.
+//!
+//! Middleware added with [`Router::with`] runs around all of it (see [`crate::middleware`]).
+//!
+
+use crate::error::{Error, Result};
+use crate::middleware::{Middleware, Next};
+use hyper::header::{HeaderValue, ALLOW};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+
+/// Future returned by a [`Handler`]
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<Response<Body>>> + Send>>;
+
+/// Answers the requests of a route
+///
+/// Implemented for every `async fn(Request<Body>) -> Result<Response<Body>>`.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, req: Request<Body>) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Request<Body>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<Response<Body>>> + Send + 'static,
+{
+    fn call(&self, req: Request<Body>) -> HandlerFuture {
+        Box::pin(self(req))
+    }
+}
+
+/// Path parameters of the matched route, kept in the request extensions
+#[derive(Debug, Clone, Default)]
+struct Params(Vec<(String, String)>);
+
+/// The path parameter `name` of the route that matched `req`, percent-decoded
+pub fn param<'a>(req: &'a Request<Body>, name: &str) -> Option<&'a str> {
+    req.extensions()
+        .get::<Params>()?
+        .0
+        .iter()
+        .find(|(param, _)| param == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[derive(Debug)]
+enum Segment {
+    Literal(String),
+    Param(String),
+}
+
+struct Route {
+    pattern: String,
+    segments: Vec<Segment>,
+    methods: Vec<Method>,
+    handler: Arc<dyn Handler>,
+}
+
+impl Route {
+    /// Parameters captured from `path`, or `None` when it does not match
+    fn capture(&self, path: &str) -> Option<Params> {
+        let parts: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = Params::default();
+        for (segment, part) in self.segments.iter().zip(parts) {
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Literal(_) => return None,
+                Segment::Param(_) if part.is_empty() => return None,
+                Segment::Param(name) => {
+                    let value = percent_encoding::percent_decode_str(part).decode_utf8().ok()?;
+                    params.0.push((name.clone(), value.into_owned()));
+                }
+            }
+        }
+        Some(params)
+    }
+
+    /// Whether the route answers `method`
+    fn allows(&self, method: &Method) -> bool {
+        self.methods.contains(method)
+    }
+}
+
+/// Every route of the server, matched in the order they were added
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
+
+impl Router {
+    pub fn new() -> Self {
+        Router::default()
+    }
+
+    /// Run `middleware` around every request, after the middleware added before it
+    pub fn with(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
+    /// Answer `methods` on `pattern` with `handler`
+    ///
+    /// A pattern may appear several times with different methods. `HEAD` is answered by
+    /// the same handler as `GET`, without the body.
+    pub fn route(mut self, pattern: &str, methods: &[Method], handler: impl Handler) -> Self {
+        let segments = pattern
+            .trim_start_matches('/')
+            .split('/')
+            .map(|segment| match segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                Some(name) => Segment::Param(name.to_string()),
+                None => Segment::Literal(segment.to_string()),
+            })
+            .collect();
+
+        self.routes.push(Route {
+            pattern: pattern.to_string(),
+            segments,
+            methods: methods.to_vec(),
+            handler: Arc::new(handler),
+        });
+        self
+    }
+
+    /// Every route as `GET, POST /path`, for the `404` body
+    pub fn listing(&self) -> Vec<String> {
+        let mut listing: Vec<(&str, Vec<&str>)> = Vec::new();
+        for route in &self.routes {
+            let methods = route.methods.iter().map(Method::as_str);
+            match listing.iter_mut().find(|(pattern, _)| *pattern == route.pattern) {
+                Some((_, known)) => known.extend(methods),
+                None => listing.push((&route.pattern, methods.collect())),
+            }
+        }
+        listing
+            .into_iter()
+            .map(|(pattern, methods)| format!("{} {}", methods.join(", "), pattern))
+            .collect()
+    }
+
+    /// Methods `path` answers, as sent in `Allow`
+    fn allowed(&self, matching: &[(&Route, Params)]) -> String {
+        let mut allowed: Vec<&str> = Vec::new();
+        for (route, _) in matching {
+            for method in &route.methods {
+                if !allowed.contains(&method.as_str()) {
+                    allowed.push(method.as_str());
+                }
+            }
+        }
+        if !allowed.contains(&"OPTIONS") {
+            allowed.push("OPTIONS");
+        }
+        allowed.join(", ")
+    }
+
+    /// Run `req` through the middleware, then hand it to the route that answers it
+    pub async fn dispatch(&'static self, req: Request<Body>) -> Result<Response<Body>> {
+        let routing = Arc::new(move |req| self.route_request(req));
+        Next::new(&self.middleware, routing).run(req).await
+    }
+
+    async fn route_request(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let matching: Vec<(&Route, Params)> = self
+            .routes
+            .iter()
+            .filter_map(|route| route.capture(req.uri().path()).map(|params| (route, params)))
+            .collect();
+        if matching.is_empty() {
+            return Err(Error::NotFound(format!(
+                "No route for {}; the routes are: {}",
+                req.uri().path(),
+                self.listing().join("; ")
+            )));
+        }
+
+        let Some((route, params)) = matching
+            .iter()
+            .find(|(route, _)| route.allows(req.method()))
+        else {
+            let allow = self.allowed(&matching);
+            if req.method() == Method::OPTIONS {
+                let allow = HeaderValue::from_str(&allow).map_err(hyper::http::Error::from)?;
+                return Ok(Response::builder()
+                    .status(StatusCode::NO_CONTENT)
+                    .header(ALLOW, allow)
+                    .body(Body::empty())?);
+            }
+            return Err(Error::MethodNotAllowed {
+                method: req.method().to_string(),
+                allow,
+            });
+        };
+
+        // hyper leaves the body out of responses to `HEAD`, keeping the headers
+        req.extensions_mut().insert(params.clone());
+        route.handler.call(req).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static MINTED: AtomicUsize = AtomicUsize::new(0);
+
+    async fn echo_kid(req: Request<Body>) -> Result<Response<Body>> {
+        let kid = param(&req, "kid").unwrap_or("-").to_string();
+        Ok(Response::new(Body::from(kid)))
+    }
+
+    async fn mint(_req: Request<Body>) -> Result<Response<Body>> {
+        MINTED.fetch_add(1, Ordering::SeqCst);
+        Ok(Response::new(Body::from("token")))
+    }
+
+    fn router() -> &'static Router {
+        Box::leak(Box::new(
+            Router::new()
+                .route("/keys/{kid}", &[Method::GET, Method::HEAD], echo_kid)
+                .route("/mint", &[Method::GET, Method::POST], mint),
+        ))
+    }
+
+    fn request(method: Method, path: &str) -> Request<Body> {
+        Request::builder().method(method).uri(path).body(Body::empty()).unwrap()
+    }
+
+    async fn body(response: Response<Body>) -> String {
+        let bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn captures_percent_decoded_parameters() {
+        let response = router().dispatch(request(Method::GET, "/keys/a%20b")).await.unwrap();
+        assert_eq!(body(response).await, "a b");
+    }
+
+    #[tokio::test]
+    async fn unknown_paths_are_404_with_the_routes() {
+        for path in ["/nope", "/keys", "/keys/", "/keys/a/b"] {
+            let error = router().dispatch(request(Method::GET, path)).await.unwrap_err();
+            assert_eq!(error.status(), StatusCode::NOT_FOUND, "{}", path);
+            assert!(error.to_string().contains("GET, HEAD /keys/{kid}"));
+        }
+    }
+
+    #[tokio::test]
+    async fn other_methods_are_405_with_allow() {
+        let error = router().dispatch(request(Method::DELETE, "/keys/a")).await.unwrap_err();
+        let response = error.into_response();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(response.headers()[ALLOW], "GET, HEAD, OPTIONS");
+    }
+
+    #[tokio::test]
+    async fn head_only_where_the_route_lists_it() {
+        let response = router().dispatch(request(Method::HEAD, "/keys/a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let minted = MINTED.load(Ordering::SeqCst);
+        let error = router().dispatch(request(Method::HEAD, "/mint")).await.unwrap_err();
+        assert_eq!(error.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(MINTED.load(Ordering::SeqCst), minted);
+    }
+
+    #[tokio::test]
+    async fn options_is_204_with_allow() {
+        let response = router().dispatch(request(Method::OPTIONS, "/mint")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        assert_eq!(response.headers()[ALLOW], "GET, POST, OPTIONS");
+        assert_eq!(MINTED.load(Ordering::SeqCst), 0);
+    }
+}
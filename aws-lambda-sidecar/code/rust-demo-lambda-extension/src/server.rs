@@ -0,0 +1,169 @@
+//! The sidecar's local HTTP server
+//!
+//! Serves [`crate::handle_request`] over TCP, or over a Unix socket, which skips the TCP
+//! stack and cannot collide with another extension's port:
+//!
+//! | Variable           | Default                                                   |
+//! |--------------------|-----------------------------------------------------------|
+//! | `LRAP_HTTP_HOST`   | `127.0.0.1`                                               |
+//! | `LRAP_HTTP_PORT`   | `8000`, `0` picks a free port                             |
+//! | `LRAP_SOCKET_PATH` | unset (TCP); a path such as `/tmp/lrap.sock` serves there instead |
+//!
+//! Caller authentication by peer credentials needs the socket, so it defaults
+//! `LRAP_SOCKET_PATH` to `/tmp/lrap.sock` (see [`crate::auth`]). The socket file is
+//! replaced at init and removed once the server drained at SHUTDOWN.
+//!
+
+use crate::auth::{Mode, Peer};
+use crate::env::{optional_var, parsed_var};
+use crate::error::{Error, Result};
+use hyper::server::accept;
+use hyper::server::conn::AddrStream;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Server;
+use log::{info, warn};
+use once_cell::sync::OnceCell;
+use std::convert::Infallible;
+use std::fs::Permissions;
+use std::future::Future;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Poll};
+use std::time::Duration;
+use tokio::net::{UnixListener, UnixStream};
+use tokio::time::Sleep;
+
+/// Pause after a failed accept, such as running out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Socket used when peer authentication asks for one and none is configured
+const DEFAULT_SOCKET_PATH: &str = "/tmp/lrap.sock";
+
+static LISTEN: OnceCell<Listen> = OnceCell::new();
+
+/// Where the local HTTP server accepts connections
+#[derive(Debug)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl Listen {
+    fn from_env() -> Result<Self> {
+        let peer_auth = crate::auth::config()?.mode == Mode::Peer;
+        let socket = optional_var("LRAP_SOCKET_PATH")?
+            .or_else(|| peer_auth.then(|| DEFAULT_SOCKET_PATH.to_string()));
+        if let Some(path) = socket {
+            return Ok(Listen::Unix(path.into()));
+        }
+
+        let host: IpAddr = parsed_var("LRAP_HTTP_HOST", IpAddr::V4(Ipv4Addr::LOCALHOST))?;
+        let port: u16 = parsed_var("LRAP_HTTP_PORT", 8000)?;
+        if port != 0 && port == crate::env::lrap_listener_port()? {
+            return Err(Error::Config(format!(
+                "LRAP_HTTP_PORT {} is already used by the Runtime API proxy (LRAP_LISTENER_PORT)",
+                port
+            )));
+        }
+        if !host.is_loopback() {
+            warn!(
+                "LRAP_HTTP_HOST {} is not a loopback address, the sidecar may be reachable from outside",
+                host
+            );
+        }
+        Ok(Listen::Tcp(SocketAddr::new(host, port)))
+    }
+}
+
+/// Get the listen address, reading it on first use
+pub fn listen() -> Result<&'static Listen> {
+    LISTEN.get_or_try_init(Listen::from_env)
+}
+
+/// A bound server, serving until `shutdown` resolves and in-flight requests are done
+pub type Serving = Pin<Box<dyn Future<Output = hyper::Result<()>> + Send>>;
+
+/// Bind the local HTTP server
+pub fn bind(shutdown: impl Future<Output = ()> + Send + 'static) -> Result<Serving> {
+    match listen()? {
+        Listen::Tcp(addr) => bind_tcp(addr, shutdown),
+        Listen::Unix(path) => bind_unix(path, shutdown),
+    }
+}
+
+/// Remove the Unix socket once the server is done with it
+pub fn cleanup() {
+    let Some(Listen::Unix(path)) = LISTEN.get() else {
+        return;
+    };
+    match std::fs::remove_file(path) {
+        Ok(()) => info!("Removed socket {}", path.display()),
+        Err(e) if e.kind() == ErrorKind::NotFound => {}
+        Err(e) => warn!("Cannot remove socket {}: {}", path.display(), e),
+    }
+}
+
+fn bind_tcp(addr: &SocketAddr, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<Serving> {
+    let builder = Server::try_bind(addr).map_err(Error::Server)?;
+
+    // TCP carries no credentials; only the `peer` mode needs them
+    let make_svc = make_service_fn(|_conn: &AddrStream| async {
+        Ok::<_, Infallible>(service_fn(|req| crate::handle_request(req, None)))
+    });
+    let server = builder.serve(make_svc);
+
+    info!("Extension HTTP server running on http://{}", server.local_addr());
+    Ok(Box::pin(server.with_graceful_shutdown(shutdown)))
+}
+
+/// Errors of a single connection, rather than of the listener
+fn is_connection_error(e: &std::io::Error) -> bool {
+    matches!(
+        e.kind(),
+        ErrorKind::ConnectionRefused | ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset
+    )
+}
+
+fn bind_unix(path: &Path, shutdown: impl Future<Output = ()> + Send + 'static) -> Result<Serving> {
+    let unavailable = |e: std::io::Error| Error::Config(format!("Cannot listen on {}: {}", path.display(), e));
+
+    // A socket left by an earlier init would make the bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path).map_err(unavailable)?;
+    }
+    let listener = UnixListener::bind(path).map_err(unavailable)?;
+    std::fs::set_permissions(path, Permissions::from_mode(0o600)).map_err(unavailable)?;
+
+    // An accept error would end the server, so log it and keep accepting, as hyper's
+    // `AddrIncoming` does
+    let display = path.display().to_string();
+    let mut backoff: Option<Pin<Box<Sleep>>> = None;
+    let incoming = accept::poll_fn(move |cx| loop {
+        if let Some(sleep) = backoff.as_mut() {
+            ready!(sleep.as_mut().poll(cx));
+            backoff = None;
+        }
+        match ready!(listener.poll_accept(cx)) {
+            Ok((stream, _)) => return Poll::Ready(Some(Ok::<_, std::io::Error>(stream))),
+            // The caller went away before we got to it; the next one may be waiting
+            Err(e) if is_connection_error(&e) => {}
+            Err(e) => {
+                warn!("Cannot accept on unix:{}, retrying in {:?}: {}", display, ACCEPT_BACKOFF, e);
+                backoff = Some(Box::pin(tokio::time::sleep(ACCEPT_BACKOFF)));
+            }
+        }
+    });
+    let make_svc = make_service_fn(|conn: &UnixStream| {
+        let peer = conn.peer_cred().ok().map(|cred| Peer {
+            uid: cred.uid(),
+            pid: cred.pid(),
+        });
+        async move { Ok::<_, Infallible>(service_fn(move |req| crate::handle_request(req, peer))) }
+    });
+
+    info!("Extension HTTP server running on unix:{}", path.display());
+    Ok(Box::pin(Server::builder(incoming).serve(make_svc).with_graceful_shutdown(shutdown)))
+}
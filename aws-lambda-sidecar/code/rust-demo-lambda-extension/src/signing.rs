@@ -0,0 +1,211 @@
+//! Signatures over arbitrary payloads, for webhooks and presigned URLs
+//!
+//! `POST /sign` signs a payload with a named key the function never sees, and
+//! `POST /verify-signature` checks one. Keys are listed like key ring entries (see
+//! [`crate::keys`]), but apart from it, so they can never sign tokens:
+//!
+//! | Variable                      | Meaning                                           |
+//! |-------------------------------|---------------------------------------------------|
+//! | `SIGNING_KEYS`                | named keys as inline JSON                         |
+//! | `SIGNING_KEYS_PATH`           | named keys as a JSON file, e.g. `/opt/keys/signing.json` |
+//! | `SIGNATURE_TOLERANCE_SECONDS` | `300`, how old (or early) a timestamp may be      |
+//!
+//! ```json
+//! [
+//!   { "kid": "webhooks", "algorithm": "HS256", "secret_env": "WEBHOOK_SECRET" },
+//!   { "kid": "urls", "algorithm": "ES256", "private_key_path": "/opt/keys/urls.pem" }
+//! ]
+//! ```
+//!
+//! Any key ring algorithm works, HMAC (`HS256`, `HS512`, ...) or asymmetric. The signature
+//! covers `{t}.{payload}`, where `t` is the Unix time of signing, and is sent as
+//! `t=1700000000,v1=<hex signature>`. Verification accepts any of several `v1` values, so
+//! receivers can roll keys, and refuses timestamps outside the tolerance so a captured
+//! request cannot be replayed later.
+//!
+
+use crate::env::parsed_var;
+use crate::error::{Error, Result};
+use crate::http::{json_body, json_response};
+use crate::keys::SigningKey;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::{crypto, Algorithm};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+static KEYS: OnceCell<HashMap<String, SigningKey>> = OnceCell::new();
+static TOLERANCE: OnceCell<u64> = OnceCell::new();
+
+/// Get the named signing keys, loading them on first use
+pub fn keys() -> Result<&'static HashMap<String, SigningKey>> {
+    KEYS.get_or_try_init(|| crate::keys::named_keys("SIGNING_KEYS", "SIGNING_KEYS_PATH"))
+}
+
+/// Seconds a signature timestamp may differ from now
+pub fn tolerance() -> Result<u64> {
+    TOLERANCE
+        .get_or_try_init(|| parsed_var("SIGNATURE_TOLERANCE_SECONDS", 300))
+        .copied()
+}
+
+/// The named key, if it may be used right now
+fn key(name: &str) -> Result<&'static SigningKey> {
+    keys()?
+        .get(name)
+        .filter(|key| key.is_active(crate::token::now()))
+        .ok_or_else(|| Error::BadRequest(format!("No active signing key named '{}'", name)))
+}
+
+/// What the signature covers
+fn signed_content(timestamp: u64, payload: &str) -> Vec<u8> {
+    format!("{}.{}", timestamp, payload).into_bytes()
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// A `t=...,v1=...` header, split into its timestamp and signatures
+struct SignatureHeader {
+    timestamp: u64,
+    signatures: Vec<Vec<u8>>,
+}
+
+impl SignatureHeader {
+    /// Parse the header; unknown schemes are ignored so new ones can be added later
+    fn parse(header: &str) -> Result<Self> {
+        let malformed =
+            || Error::InvalidSignature("expected t=<seconds>,v1=<hex signature>".to_string());
+
+        let mut timestamp = None;
+        let mut signatures = Vec::new();
+        for item in header.split(',') {
+            let (scheme, value) = item.trim().split_once('=').ok_or_else(malformed)?;
+            match scheme {
+                "t" => timestamp = Some(value.parse().map_err(|_| malformed())?),
+                "v1" => signatures.push(from_hex(value).ok_or_else(malformed)?),
+                _ => {}
+            }
+        }
+
+        match (timestamp, signatures.is_empty()) {
+            (Some(timestamp), false) => Ok(SignatureHeader { timestamp, signatures }),
+            _ => Err(malformed()),
+        }
+    }
+}
+
+/// Payload to sign with a named key
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SignRequest {
+    key: String,
+    payload: String,
+}
+
+#[derive(Serialize)]
+struct SignResponse {
+    key: String,
+    algorithm: Algorithm,
+    timestamp: u64,
+    signature: String,
+}
+
+/// Payload and signature to check against a named key
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifySignatureRequest {
+    key: String,
+    payload: String,
+    signature: String,
+}
+
+#[derive(Serialize)]
+struct VerifySignatureResponse {
+    valid: bool,
+    timestamp: u64,
+}
+
+/// `POST /sign`
+///
+/// Returns the `t=...,v1=...` header to send along with the payload.
+///
+pub async fn handle_sign(req: Request<Body>) -> Result<Response<Body>> {
+    let request: SignRequest = json_body(req)
+        .await?
+        .ok_or_else(|| Error::BadRequest("Missing JSON body with key and payload".to_string()))?;
+    let key = key(&request.key)?;
+
+    let timestamp = crate::token::now();
+    let content = signed_content(timestamp, &request.payload);
+    let signature = crypto::sign(&content, key.encoding_key(), key.algorithm)?;
+    let signature = URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|e| Error::Config(format!("Unexpected signature encoding: {}", e)))?;
+
+    json_response(
+        StatusCode::OK,
+        &SignResponse {
+            key: request.key,
+            algorithm: key.algorithm,
+            timestamp,
+            signature: format!("t={},v1={}", timestamp, to_hex(&signature)),
+        },
+    )
+}
+
+/// `POST /verify-signature`
+///
+/// Answers 200 when one of the `v1` signatures matches and the timestamp is recent, and a
+/// 401 naming the failed check otherwise.
+///
+pub async fn handle_verify_signature(req: Request<Body>) -> Result<Response<Body>> {
+    let request: VerifySignatureRequest = json_body(req)
+        .await?
+        .ok_or_else(|| Error::BadRequest("Missing JSON body with key, payload and signature".to_string()))?;
+    let key = key(&request.key)?;
+    let header = SignatureHeader::parse(&request.signature)?;
+
+    let age = crate::token::now().abs_diff(header.timestamp);
+    if age > tolerance()? {
+        return Err(Error::SignatureExpired(format!(
+            "timestamp is {} seconds away from now, more than the {} allowed",
+            age,
+            tolerance()?
+        )));
+    }
+
+    let content = signed_content(header.timestamp, &request.payload);
+    let mut valid = false;
+    for signature in &header.signatures {
+        let signature = URL_SAFE_NO_PAD.encode(signature);
+        valid |= crypto::verify(&signature, &content, key.decoding_key(), key.algorithm)?;
+    }
+    if !valid {
+        return Err(Error::InvalidSignature(format!(
+            "no v1 signature matches the key '{}'",
+            request.key
+        )));
+    }
+
+    json_response(
+        StatusCode::OK,
+        &VerifySignatureResponse {
+            valid: true,
+            timestamp: header.timestamp,
+        },
+    )
+}
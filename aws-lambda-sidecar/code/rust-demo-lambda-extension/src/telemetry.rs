@@ -0,0 +1,324 @@
+//! Lambda Telemetry API subscription
+//!
+//! When `LRAP_TELEMETRY_SINK` is set, the extension runs a local listener, subscribes it
+//! to the Telemetry API and forwards every batch of `platform.*`, `function` and
+//! `extension` records to a [`TelemetrySink`].
+//!
+//! | Variable                      | Default                        |
+//! |-------------------------------|--------------------------------|
+//! | `LRAP_TELEMETRY_SINK`         | unset (disabled), `stdout`, `http` |
+//! | `LRAP_TELEMETRY_HTTP_URL`     | required for the `http` sink   |
+//! | `LRAP_TELEMETRY_PORT`         | `4243`                         |
+//! | `LRAP_TELEMETRY_TYPES`        | `platform,function,extension`  |
+//! | `LRAP_TELEMETRY_MAX_ITEMS`    | `1000`                         |
+//! | `LRAP_TELEMETRY_MAX_BYTES`    | `262144`                       |
+//! | `LRAP_TELEMETRY_TIMEOUT_MS`   | `1000`                         |
+//! | `LRAP_TELEMETRY_QUEUE`        | `64` batches                   |
+//!
+//! Batches wait in a queue of `LRAP_TELEMETRY_QUEUE` batches while the sink delivers
+//! earlier ones. When the sink falls that far behind, new batches are dropped and counted,
+//! rather than held in memory the function also needs.
+//!
+
+use crate::env::{optional_var, parsed_var};
+use crate::error::{Error, Result};
+use crate::extension::{extension_id, send_request};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use std::convert::Infallible;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+/// Lambda Telemetry API version
+const TELEMETRY_API_VERSION: &str = "2022-07-01";
+
+/// Schema of the records we ask Lambda to deliver
+const TELEMETRY_SCHEMA_VERSION: &str = "2022-12-13";
+
+/// Batches dropped because the queue was full, since init
+static DROPPED_BATCHES: AtomicU64 = AtomicU64::new(0);
+
+/// One record delivered by the Telemetry API
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TelemetryEvent {
+    pub time: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub record: serde_json::Value,
+}
+
+/// Future returned by [`TelemetrySink::send`]
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<()>> + Send + 'a>>;
+
+/// Destination for telemetry batches
+pub trait TelemetrySink: Send + Sync + 'static {
+    /// Deliver one batch, in the order Lambda sent it
+    fn send<'a>(&'a self, batch: &'a [TelemetryEvent]) -> SinkFuture<'a>;
+}
+
+/// Sink writing one JSON line per record to stdout
+///
+/// `extension` records are skipped: our own stdout is captured as extension
+/// logs, so echoing them would feed the subscription forever.
+pub struct StdoutSink;
+
+impl TelemetrySink for StdoutSink {
+    fn send<'a>(&'a self, batch: &'a [TelemetryEvent]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            for event in batch.iter().filter(|e| e.kind != "extension") {
+                println!("{}", serde_json::to_string(event)?);
+            }
+            Ok(())
+        })
+    }
+}
+
+/// Sink POSTing each batch as a JSON array to an HTTP endpoint
+pub struct HttpSink {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl HttpSink {
+    pub fn new(url: String) -> Self {
+        HttpSink {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+}
+
+impl TelemetrySink for HttpSink {
+    fn send<'a>(&'a self, batch: &'a [TelemetryEvent]) -> SinkFuture<'a> {
+        Box::pin(async move {
+            self.client
+                .post(&self.url)
+                .json(batch)
+                .send()
+                .await
+                .and_then(reqwest::Response::error_for_status)
+                .map_err(|e| Error::Telemetry(format!("POST {} failed: {}", self.url, e)))?;
+            Ok(())
+        })
+    }
+}
+
+/// Buffering limits passed to the Telemetry API subscription
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Buffering {
+    pub max_items: u32,
+    pub max_bytes: u32,
+    pub timeout_ms: u32,
+}
+
+impl Buffering {
+    /// Read the buffering limits from the environment, within the ranges Lambda accepts
+    fn from_env() -> Result<Self> {
+        let buffering = Buffering {
+            max_items: parsed_var("LRAP_TELEMETRY_MAX_ITEMS", 1_000)?,
+            max_bytes: parsed_var("LRAP_TELEMETRY_MAX_BYTES", 262_144)?,
+            timeout_ms: parsed_var("LRAP_TELEMETRY_TIMEOUT_MS", 1_000)?,
+        };
+
+        let in_range = (1_000..=10_000).contains(&buffering.max_items)
+            && (262_144..=1_048_576).contains(&buffering.max_bytes)
+            && (25..=30_000).contains(&buffering.timeout_ms);
+        if !in_range {
+            return Err(Error::Config(format!(
+                "Telemetry buffering out of range (maxItems 1000-10000, maxBytes 262144-1048576, timeoutMs 25-30000): {:?}",
+                buffering
+            )));
+        }
+
+        Ok(buffering)
+    }
+}
+
+/// Running telemetry subscription
+pub struct Telemetry {
+    drain: oneshot::Sender<()>,
+    listener: JoinHandle<Result<()>>,
+    dispatcher: JoinHandle<()>,
+    buffering: Buffering,
+}
+
+/// Build the configured sink, or `None` when telemetry is disabled
+fn sink_from_env() -> Result<Option<Arc<dyn TelemetrySink>>> {
+    match optional_var("LRAP_TELEMETRY_SINK")?.as_deref() {
+        None | Some("") => Ok(None),
+        Some("stdout") => Ok(Some(Arc::new(StdoutSink))),
+        Some("http") => {
+            let url = optional_var("LRAP_TELEMETRY_HTTP_URL")?.ok_or_else(|| {
+                Error::Config("LRAP_TELEMETRY_HTTP_URL is required for the http telemetry sink".to_string())
+            })?;
+            Ok(Some(Arc::new(HttpSink::new(url))))
+        }
+        Some(other) => Err(Error::Config(format!("Unknown LRAP_TELEMETRY_SINK: {}", other))),
+    }
+}
+
+/// Start the listener and subscribe it to the Telemetry API
+///
+/// Must run after registration and before the first `/event/next`, since Lambda
+/// only accepts subscriptions during the init phase. Returns `None` when
+/// telemetry is disabled.
+///
+pub async fn start() -> Result<Option<Telemetry>> {
+    let Some(sink) = sink_from_env()? else {
+        return Ok(None);
+    };
+
+    let buffering = Buffering::from_env()?;
+    let port: u16 = parsed_var("LRAP_TELEMETRY_PORT", 4243)?;
+    let types = optional_var("LRAP_TELEMETRY_TYPES")?
+        .unwrap_or_else(|| "platform,function,extension".to_string());
+    let types: Vec<&str> = types.split(',').map(str::trim).filter(|t| !t.is_empty()).collect();
+
+    // Lambda delivers to the sandbox hostname, so listen on every interface
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let builder = Server::try_bind(&addr).map_err(Error::Server)?;
+
+    let queue: usize = parsed_var("LRAP_TELEMETRY_QUEUE", 64)?;
+    if queue == 0 {
+        return Err(Error::Config("LRAP_TELEMETRY_QUEUE must be at least 1".to_string()));
+    }
+    let (sender, receiver) = mpsc::channel(queue);
+    let (drain, drain_rx) = oneshot::channel::<()>();
+
+    let make_svc = make_service_fn(move |_conn| {
+        let sender = sender.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req| {
+                let sender = sender.clone();
+                async move {
+                    Ok::<_, Infallible>(receive(sender, req).await.unwrap_or_else(Error::into_response))
+                }
+            }))
+        }
+    });
+
+    let server = builder.serve(make_svc).with_graceful_shutdown(async {
+        let _ = drain_rx.await;
+    });
+    let listener = tokio::spawn(async move { server.await.map_err(Error::Server) });
+    let dispatcher = tokio::spawn(dispatch(sink, receiver));
+
+    subscribe(port, &types, buffering).await?;
+    info!("Telemetry API listener running on http://{} for {:?}", addr, types);
+
+    Ok(Some(Telemetry {
+        drain,
+        listener,
+        dispatcher,
+        buffering,
+    }))
+}
+
+/// Subscribe the local listener with the Telemetry API
+async fn subscribe(port: u16, types: &[&str], buffering: Buffering) -> Result<()> {
+    let body = serde_json::json!({
+        "schemaVersion": TELEMETRY_SCHEMA_VERSION,
+        "destination": {
+            "protocol": "HTTP",
+            "URI": format!("http://sandbox.localdomain:{}", port),
+        },
+        "types": types,
+        "buffering": buffering,
+    });
+
+    let uri = hyper::Uri::builder()
+        .scheme("http")
+        .authority(crate::env::sandbox_runtime_api()?)
+        .path_and_query(format!("/{}/telemetry", TELEMETRY_API_VERSION))
+        .build()?;
+
+    let request = Request::builder()
+        .method(Method::PUT)
+        .uri(uri)
+        .header("Lambda-Extension-Identifier", extension_id()?)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body.to_string()))?;
+
+    let response = send_request(request).await?;
+    if !response.status().is_success() {
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await?;
+        return Err(Error::Protocol(format!(
+            "Telemetry API subscription failed with {}: {}",
+            status,
+            String::from_utf8_lossy(&body)
+        )));
+    }
+
+    Ok(())
+}
+
+/// Accept one batch from Lambda and queue it for the sink
+async fn receive(
+    sender: mpsc::Sender<Vec<TelemetryEvent>>,
+    req: Request<Body>,
+) -> Result<Response<Body>> {
+    let body = hyper::body::to_bytes(req.into_body()).await?;
+    let batch: Vec<TelemetryEvent> = serde_json::from_slice(&body)
+        .map_err(|e| Error::Protocol(format!("Cannot parse telemetry batch: {}", e)))?;
+
+    debug!("Received {} telemetry records", batch.len());
+    match sender.try_send(batch) {
+        Ok(()) => {}
+        Err(TrySendError::Full(batch)) => {
+            let dropped = DROPPED_BATCHES.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!(
+                "Telemetry queue full, dropping {} records ({} batches dropped so far)",
+                batch.len(),
+                dropped
+            );
+        }
+        Err(TrySendError::Closed(_)) => warn!("Telemetry dispatcher stopped, dropping batch"),
+    }
+
+    Ok(Response::builder().status(StatusCode::OK).body(Body::empty())?)
+}
+
+/// Hand queued batches to the sink until every sender is gone
+async fn dispatch(sink: Arc<dyn TelemetrySink>, mut receiver: mpsc::Receiver<Vec<TelemetryEvent>>) {
+    while let Some(batch) = receiver.recv().await {
+        if let Err(e) = sink.send(&batch).await {
+            warn!("Telemetry sink dropped {} records: {}", batch.len(), e);
+        }
+    }
+}
+
+impl Telemetry {
+    /// Wait for Lambda's last batch, then deliver everything still queued
+    ///
+    /// Lambda flushes its buffer after SHUTDOWN, so the listener stays up for one
+    /// buffering timeout (bounded by `deadline`) before it is drained.
+    ///
+    pub async fn flush(self, deadline: Instant) {
+        let last_batch = Instant::now() + Duration::from_millis(self.buffering.timeout_ms.into());
+        tokio::time::sleep_until(last_batch.min(deadline).into()).await;
+
+        let _ = self.drain.send(());
+        let flushed = async {
+            if let Ok(Err(e)) = self.listener.await {
+                warn!("Telemetry listener failed: {}", e);
+            }
+            let _ = self.dispatcher.await;
+        };
+
+        match tokio::time::timeout_at(deadline.into(), flushed).await {
+            Ok(()) => info!("Telemetry flushed"),
+            Err(_) => warn!("Shutdown deadline reached before telemetry was flushed"),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+//! Token issuance for `/my-token`
+//!
+//! The subject comes from the request, the registered claims from configuration. JWTs
+//! can also be encrypted on request (see [`crate::jwe`]), PASETO tokens issued instead of
+//! JWTs (see [`crate::paseto`]), and tokens bound to a `DPoP` proof's key (see [`crate::dpop`]):
+//!
+//! | Variable             | Default                               |
+//! |----------------------|---------------------------------------|
+//! | `JWT_TTL_SECONDS`    | `3600`                                |
+//! | `JWT_ISSUER`         | the extension name                    |
+//! | `JWT_AUDIENCE`       | unset (no `aud`), comma separated     |
+//! | `JWT_ALLOWED_CLAIMS` | unset (no custom claims), comma separated |
+//! | `MY_TOKEN_FORMAT`    | `jwt`, or `v4.public` / `v4.local`    |
+//!
+
+use crate::env::{list_var, optional_var, parsed_var};
+use crate::error::{Error, Result};
+use crate::http::{json_body, json_response, query};
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::encode;
+use log::info;
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Claims the sidecar sets itself and that requests may not override
+pub const REGISTERED_CLAIMS: [&str; 8] = ["iss", "sub", "aud", "exp", "nbf", "iat", "jti", "cnf"];
+
+/// Longest subject accepted from a request
+const MAX_SUBJECT_LEN: usize = 256;
+
+static CONFIG: OnceCell<TokenConfig> = OnceCell::new();
+
+/// Token settings read once from the environment
+#[derive(Debug)]
+pub struct TokenConfig {
+    pub ttl: u64,
+    pub issuer: String,
+    pub audience: Vec<String>,
+    pub allowed_claims: HashSet<String>,
+    /// Format of the tokens `/my-token` issues
+    pub format: TokenFormat,
+}
+
+impl TokenConfig {
+    fn from_env() -> Result<Self> {
+        let ttl: u64 = parsed_var("JWT_TTL_SECONDS", 3600)?;
+        if ttl == 0 {
+            return Err(Error::Config("JWT_TTL_SECONDS must be greater than zero".to_string()));
+        }
+
+        let allowed_claims: HashSet<String> = list_var("JWT_ALLOWED_CLAIMS")?.into_iter().collect();
+        if let Some(claim) = allowed_claims.iter().find(|c| REGISTERED_CLAIMS.contains(&c.as_str())) {
+            return Err(Error::Config(format!(
+                "JWT_ALLOWED_CLAIMS cannot include the registered claim '{}'",
+                claim
+            )));
+        }
+
+        let format = parsed_var("MY_TOKEN_FORMAT", TokenFormat::Jwt)?;
+        format.check_key()?;
+
+        Ok(TokenConfig {
+            ttl,
+            issuer: optional_var("JWT_ISSUER")?.unwrap_or_else(|| crate::EXTENSION_NAME.to_string()),
+            audience: list_var("JWT_AUDIENCE")?,
+            allowed_claims,
+            format,
+        })
+    }
+}
+
+/// Kind of token issued for a set of claims
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+pub enum TokenFormat {
+    #[default]
+    #[serde(rename = "jwt")]
+    Jwt,
+    #[serde(rename = "v4.public")]
+    V4Public,
+    #[serde(rename = "v4.local")]
+    V4Local,
+}
+
+impl FromStr for TokenFormat {
+    type Err = serde_json::Error;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        serde_json::from_value(Value::String(s.to_owned()))
+    }
+}
+
+impl TokenFormat {
+    /// Make sure the key ring has a key for this format; JWTs can use any key
+    pub fn check_key(self) -> Result<()> {
+        match self {
+            TokenFormat::Jwt => Ok(()),
+            TokenFormat::V4Public | TokenFormat::V4Local => crate::paseto::key(self).map(|_| ()),
+        }
+    }
+}
+
+/// Get the token configuration, loading it on first use
+pub fn config() -> Result<&'static TokenConfig> {
+    CONFIG.get_or_try_init(TokenConfig::from_env)
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Claims carried by every token the sidecar issues
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub iss: String,
+    pub sub: String,
+    #[serde(
+        default,
+        skip_serializing_if = "Vec::is_empty",
+        serialize_with = "serialize_audience",
+        deserialize_with = "deserialize_audience"
+    )]
+    pub aud: Vec<String>,
+    pub exp: u64,
+    pub nbf: u64,
+    pub iat: u64,
+    pub jti: String,
+    #[serde(flatten)]
+    pub extra: Map<String, Value>,
+}
+
+impl Claims {
+    /// Registered claims for `sub`, valid from now for the configured TTL
+    pub fn new(sub: String, config: &TokenConfig) -> Self {
+        let iat = now();
+        Claims {
+            iss: config.issuer.clone(),
+            sub,
+            aud: config.audience.clone(),
+            exp: iat + config.ttl,
+            nbf: iat,
+            iat,
+            jti: uuid::Uuid::new_v4().to_string(),
+            extra: Map::new(),
+        }
+    }
+}
+
+/// Sign `claims` with the current primary key
+pub fn sign(claims: &Claims) -> Result<String> {
+    let key = crate::keys::signing_key()?;
+    Ok(encode(&key.header(), claims, key.encoding_key())?)
+}
+
+/// Issue `claims` as a token in `format`, wrapping a JWT in a JWE when `encrypt` is set
+pub fn issue(claims: &Claims, format: TokenFormat, encrypt: bool) -> Result<String> {
+    match format {
+        TokenFormat::Jwt if encrypt => crate::jwe::encrypt(&sign(claims)?),
+        TokenFormat::Jwt => sign(claims),
+        TokenFormat::V4Public if encrypt => Err(Error::BadRequest(
+            "v4.public tokens cannot be encrypted, use v4.local".to_string(),
+        )),
+        TokenFormat::V4Public | TokenFormat::V4Local => crate::paseto::issue(claims, format),
+    }
+}
+
+/// A single audience is written as a string, several as an array (RFC 7519 §4.1.3)
+fn serialize_audience<S: Serializer>(aud: &[String], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    match aud {
+        [single] => serializer.serialize_str(single),
+        _ => aud.serialize(serializer),
+    }
+}
+
+fn deserialize_audience<'de, D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Audience {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match Audience::deserialize(deserializer)? {
+        Audience::One(aud) => vec![aud],
+        Audience::Many(aud) => aud,
+    })
+}
+
+/// Subject and encryption asked for in the query string
+#[derive(Debug, Default, Deserialize)]
+struct TokenQuery {
+    sub: Option<String>,
+    encrypt: Option<bool>,
+}
+
+/// Subject and custom claims asked for in the JSON body
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TokenRequest {
+    sub: Option<String>,
+    #[serde(default)]
+    claims: Map<String, Value>,
+    #[serde(default)]
+    encrypt: bool,
+}
+
+#[derive(Serialize)]
+struct TokenResponse {
+    token: String,
+    /// `DPoP` when the token is bound to the caller's key, `Bearer` otherwise
+    token_type: &'static str,
+    expires_at: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    refresh_token: Option<String>,
+    user_id: String,
+    message: String,
+}
+
+/// Check the caller's custom claims against the configured allowlist
+fn validate_extra_claims(extra: &Map<String, Value>, config: &TokenConfig) -> Result<()> {
+    for name in extra.keys() {
+        if REGISTERED_CLAIMS.contains(&name.as_str()) {
+            return Err(Error::BadRequest(format!("Claim '{}' is set by the sidecar", name)));
+        }
+        if !config.allowed_claims.contains(name) {
+            return Err(Error::BadRequest(format!("Claim '{}' is not allowed", name)));
+        }
+    }
+    Ok(())
+}
+
+/// Issue a token for the subject in the query string (`?sub=`) or JSON body
+///
+/// The JSON body may also carry custom `claims`; the query string wins for `sub` and
+/// `encrypt`. The response includes a refresh token to redeem at `/oauth/token`, unless they
+/// are disabled; the access tokens it brings are encrypted like the first one, and bound to
+/// the same DPoP key.
+///
+pub async fn handle_my_token(req: Request<Body>) -> Result<Response<Body>> {
+    let config = config()?;
+
+    let jkt = crate::dpop::bind(&req)?;
+    let from_query: TokenQuery = query(&req)?;
+    let from_body: TokenRequest = json_body(req).await?.unwrap_or_default();
+
+    let sub = from_query
+        .sub
+        .or(from_body.sub)
+        .map(|s| s.trim().to_owned())
+        .filter(|s| !s.is_empty())
+        .ok_or_else(|| Error::BadRequest("Missing 'sub' in query string or JSON body".to_string()))?;
+    if sub.len() > MAX_SUBJECT_LEN {
+        return Err(Error::BadRequest(format!(
+            "'sub' is longer than {} characters",
+            MAX_SUBJECT_LEN
+        )));
+    }
+
+    let encrypt = from_query.encrypt.unwrap_or(from_body.encrypt);
+    let extra = from_body.claims;
+    validate_extra_claims(&extra, config)?;
+
+    let mut claims = Claims::new(sub, config);
+    claims.extra = extra;
+
+    let mut grant = crate::refresh::Grant::new(claims.sub.clone(), None, claims.aud.clone(), claims.extra.clone());
+    grant.format = config.format;
+    grant.encrypted = encrypt;
+    grant.jkt = jkt.clone();
+    if let Some(jkt) = &jkt {
+        crate::dpop::confirm(&mut claims.extra, jkt);
+    }
+
+    if let Some(invoke) = crate::extension::current_invoke() {
+        info!(
+            "Issuing token {} during invocation {} of {}",
+            claims.jti, invoke.request_id, invoke.invoked_function_arn
+        );
+    }
+
+    let token = issue(&claims, config.format, encrypt)?;
+    let refresh_token = crate::refresh::issue(grant).await?;
+
+    json_response(
+        StatusCode::OK,
+        &TokenResponse {
+            token,
+            token_type: match jkt {
+                Some(_) => "DPoP",
+                None => "Bearer",
+            },
+            expires_at: claims.exp,
+            refresh_token,
+            user_id: claims.sub,
+            message: match config.format {
+                TokenFormat::Jwt => "JWT token generated successfully",
+                TokenFormat::V4Public | TokenFormat::V4Local => "PASETO token generated successfully",
+            }
+            .to_string(),
+        },
+    )
+}
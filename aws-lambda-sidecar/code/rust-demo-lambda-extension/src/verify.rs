@@ -0,0 +1,294 @@
+//! Token verification for `/verify`
+//!
+//! Checks the signature with the key ring entry named by the token's `kid`, then `exp`,
+//! `nbf`, `iss` and `aud`, and finally that the `jti` was not revoked. Encrypted tokens
+//! are decrypted with the JWE key first (see [`crate::jwe`]), and PASETO tokens get the
+//! same checks (see [`crate::paseto`]).
+//!
+//! A token bound with `cnf.jkt` is only valid along with a DPoP proof from its key (see
+//! [`crate::dpop`]), sent as `Authorization: DPoP <token>` and a `DPoP` header, or as
+//! `token` and `dpop` in the JSON body. The body names the request the proof was made
+//! for in `htm` and `htu`, since it is the gateway's, not the one to `/verify`:
+//!
+//! ```json
+//! { "token": "eyJ...", "dpop": "eyJ...", "htm": "GET", "htu": "https://orders.example.com/orders" }
+//! ```
+//!
+//!
+//! The other settings:
+//!
+//! | Variable              | Default                         |
+//! |-----------------------|---------------------------------|
+//! | `JWT_VERIFY_ISSUERS`  | `JWT_ISSUER`, comma separated   |
+//! | `JWT_VERIFY_AUDIENCE` | `JWT_AUDIENCE`, comma separated; unset skips the `aud` check |
+//! | `JWT_LEEWAY_SECONDS`  | `30`                            |
+//!
+
+use crate::env::{list_var, parsed_var};
+use crate::error::{Error, Result};
+use crate::http::{json_body, json_response};
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Request, Response, StatusCode};
+use jsonwebtoken::errors::ErrorKind;
+use jsonwebtoken::{decode, decode_header, Validation};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+static CONFIG: OnceCell<VerifyConfig> = OnceCell::new();
+
+/// Rules a presented token must satisfy
+#[derive(Debug)]
+pub struct VerifyConfig {
+    pub issuers: Vec<String>,
+    pub audience: Vec<String>,
+    pub leeway: u64,
+}
+
+impl VerifyConfig {
+    fn from_env() -> Result<Self> {
+        let token = crate::token::config()?;
+
+        let issuers = match list_var("JWT_VERIFY_ISSUERS")? {
+            issuers if issuers.is_empty() => vec![token.issuer.clone()],
+            issuers => issuers,
+        };
+        let audience = match list_var("JWT_VERIFY_AUDIENCE")? {
+            audience if audience.is_empty() => token.audience.clone(),
+            audience => audience,
+        };
+
+        Ok(VerifyConfig {
+            issuers,
+            audience,
+            leeway: parsed_var("JWT_LEEWAY_SECONDS", 30)?,
+        })
+    }
+}
+
+/// Get the verification rules, loading them on first use
+pub fn config() -> Result<&'static VerifyConfig> {
+    CONFIG.get_or_try_init(VerifyConfig::from_env)
+}
+
+/// A token that passed every check
+#[derive(Debug, Serialize)]
+pub struct Verified {
+    /// JOSE header of a JWT, or version, purpose and key id of a PASETO token
+    pub header: Value,
+    pub claims: Map<String, Value>,
+    /// Whether the claims were encrypted, in a JWE or a `v4.local` token
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub encrypted: bool,
+}
+
+/// Whether `token` looks like a self-contained access token (JWS, JWE or PASETO), not a refresh token
+pub fn is_access_token(token: &str) -> bool {
+    crate::paseto::is_paseto(token) || matches!(token.split('.').count(), 3 | 5)
+}
+
+/// Check `token`, decrypting it first when it is a JWE
+pub fn validate(token: &str) -> Result<Verified> {
+    if crate::paseto::is_paseto(token) {
+        return validate_paseto(token);
+    }
+    if !crate::jwe::is_jwe(token) {
+        return validate_signed(token);
+    }
+
+    let verified = validate_signed(&crate::jwe::decrypt(token)?)?;
+    Ok(Verified {
+        encrypted: true,
+        ..verified
+    })
+}
+
+/// Check a signed token against the key it names and the configured rules
+fn validate_signed(token: &str) -> Result<Verified> {
+    let config = config()?;
+    let kid = decode_header(token).map_err(Error::InvalidToken)?.kid;
+    let key = crate::keys::key_ring()?
+        .verification_key(kid.as_deref(), crate::token::now())
+        .ok_or_else(|| match &kid {
+            Some(kid) => Error::UnknownKey(format!("no active key with kid '{}'", kid)),
+            None => Error::UnknownKey("no active key".to_string()),
+        })?;
+
+    let mut validation = Validation::new(key.algorithm);
+    validation.leeway = config.leeway;
+    validation.validate_nbf = true;
+    validation.set_issuer(&config.issuers);
+    if config.audience.is_empty() {
+        validation.validate_aud = false;
+        validation.set_required_spec_claims(&["exp", "iss"]);
+    } else {
+        validation.set_audience(&config.audience);
+        validation.set_required_spec_claims(&["exp", "iss", "aud"]);
+    }
+
+    let data = decode::<Map<String, Value>>(token, key.decoding_key(), &validation)
+        .map_err(Error::InvalidToken)?;
+    check_revoked(&data.claims)?;
+
+    Ok(Verified {
+        header: serde_json::to_value(data.header)?,
+        claims: data.claims,
+        encrypted: false,
+    })
+}
+
+/// Check a PASETO token, and its claims the way jsonwebtoken checks a JWT's
+fn validate_paseto(token: &str) -> Result<Verified> {
+    let config = config()?;
+    let opened = crate::paseto::open(token)?;
+    let claims = &opened.claims;
+    let reject = |kind: ErrorKind| Err(Error::InvalidToken(kind.into()));
+    let now = crate::token::now();
+
+    match claims.get("exp").and_then(Value::as_u64) {
+        None => return reject(ErrorKind::MissingRequiredClaim("exp".to_string())),
+        Some(exp) if exp.saturating_add(config.leeway) < now => return reject(ErrorKind::ExpiredSignature),
+        Some(_) => {}
+    }
+    if claims
+        .get("nbf")
+        .and_then(Value::as_u64)
+        .is_some_and(|nbf| nbf > now.saturating_add(config.leeway))
+    {
+        return reject(ErrorKind::ImmatureSignature);
+    }
+    match claims.get("iss").and_then(Value::as_str) {
+        None => return reject(ErrorKind::MissingRequiredClaim("iss".to_string())),
+        Some(iss) if !config.issuers.iter().any(|issuer| issuer == iss) => {
+            return reject(ErrorKind::InvalidIssuer)
+        }
+        Some(_) => {}
+    }
+    if !config.audience.is_empty() {
+        let audience: Vec<&str> = match claims.get("aud") {
+            None => return reject(ErrorKind::MissingRequiredClaim("aud".to_string())),
+            Some(Value::String(aud)) => vec![aud.as_str()],
+            Some(Value::Array(aud)) => aud.iter().filter_map(Value::as_str).collect(),
+            Some(_) => return reject(ErrorKind::InvalidAudience),
+        };
+        if !audience.iter().any(|aud| config.audience.iter().any(|allowed| allowed == aud)) {
+            return reject(ErrorKind::InvalidAudience);
+        }
+    }
+    check_revoked(claims)?;
+
+    Ok(Verified {
+        header: opened.header,
+        claims: opened.claims,
+        encrypted: opened.encrypted,
+    })
+}
+
+/// Refuse a token whose `jti` is on the denylist
+fn check_revoked(claims: &Map<String, Value>) -> Result<()> {
+    if let Some(jti) = claims.get("jti").and_then(Value::as_str) {
+        if crate::revocation::denylist()?.is_revoked(jti) {
+            return Err(Error::TokenRevoked(format!("token {} was revoked", jti)));
+        }
+    }
+    Ok(())
+}
+
+/// Token, and the DPoP proof sent with it, passed in the JSON body
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct VerifyRequest {
+    token: Option<String>,
+    dpop: Option<String>,
+    /// Method and URL of the request the proof was made for
+    htm: Option<String>,
+    htu: Option<String>,
+}
+
+#[derive(Serialize)]
+struct VerifyResponse {
+    valid: bool,
+    #[serde(flatten)]
+    verified: Verified,
+}
+
+/// A token as presented to `/verify`, with whatever DPoP proof came along
+struct Presented {
+    token: String,
+    /// Whether it came as `Authorization: Bearer`, which a bound token must not
+    bearer: bool,
+    proof: Option<String>,
+    htm: Option<String>,
+    htu: Option<String>,
+}
+
+/// Take the token from `Authorization: Bearer` or `DPoP`, falling back to the JSON body
+async fn presented_token(req: Request<Body>) -> Result<Presented> {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned);
+    let header_proof = crate::dpop::proof_header(&req)?;
+    let body: VerifyRequest = json_body(req).await?.unwrap_or_default();
+
+    let from_header = authorization.as_deref().and_then(|v| {
+        v.strip_prefix("Bearer ")
+            .map(|token| (token, true))
+            .or_else(|| v.strip_prefix("DPoP ").map(|token| (token, false)))
+    });
+    let (token, bearer) = match from_header {
+        Some((token, bearer)) => (Some(token.trim().to_owned()), bearer),
+        None => (body.token, false),
+    };
+    let token = token
+        .ok_or_else(|| Error::BadRequest("Missing token in Authorization header or JSON body".to_string()))?;
+
+    Ok(Presented {
+        token,
+        bearer,
+        proof: header_proof.or(body.dpop),
+        htm: body.htm,
+        htu: body.htu,
+    })
+}
+
+/// Require a proof from the key a bound token names, and refuse proofs for bearer tokens
+fn check_binding(verified: &Verified, presented: &Presented) -> Result<()> {
+    let invalid = |reason: &str| Err(Error::InvalidDpopProof(reason.to_string()));
+
+    let Some(jkt) = crate::dpop::bound_key(&verified.claims) else {
+        return match presented.proof {
+            Some(_) => invalid("token is not DPoP bound"),
+            None => Ok(()),
+        };
+    };
+    if presented.bearer {
+        return invalid("token is DPoP bound, send it as Authorization: DPoP");
+    }
+    let Some(proof) = &presented.proof else {
+        return invalid("token is DPoP bound, send the proof made for the request");
+    };
+    let (Some(htm), Some(htu)) = (&presented.htm, &presented.htu) else {
+        return Err(Error::BadRequest(
+            "Name the request the DPoP proof was made for in htm and htu".to_string(),
+        ));
+    };
+
+    if crate::dpop::check_proof(proof, htm, htu, Some(&presented.token))? != jkt {
+        return invalid("proof is signed with another key than the token is bound to");
+    }
+    Ok(())
+}
+
+/// `POST /verify`
+///
+/// Returns the decoded header and claims, or a 401 naming the failed check.
+///
+pub async fn handle_verify(req: Request<Body>) -> Result<Response<Body>> {
+    let presented = presented_token(req).await?;
+    let verified = validate(&presented.token)?;
+    check_binding(&verified, &presented)?;
+
+    json_response(StatusCode::OK, &VerifyResponse { valid: true, verified })
+}